@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+use crate::config::AccelerationConfig;
+
+/// Picks the volume step for each turn of the knob from how fast it is being turned.
+#[derive(Debug, Default)]
+pub struct Accelerator {
+    /// When the previous turn arrived, and whether it was upwards.
+    last: Option<(Instant, bool)>,
+}
+
+impl Accelerator {
+    /// The step for a turn at `at`, in percent: that of the quickest level whose `within_ms`
+    /// covers the time since the previous turn in the same direction, or the fine step.
+    pub fn step(&mut self, config: &AccelerationConfig, up: bool, at: Instant) -> f64 {
+        let elapsed = match self.last.replace((at, up)) {
+            Some((previous, previous_up)) if previous_up == up => {
+                at.saturating_duration_since(previous)
+            }
+            _ => return config.fine_step,
+        };
+        config
+            .levels
+            .iter()
+            .filter(|level| elapsed <= Duration::from_millis(level.within_ms))
+            .min_by_key(|level| level.within_ms)
+            .map_or(config.fine_step, |level| level.step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Turns at the given milliseconds from the start, returning the steps picked.
+    fn steps(turns: &[(u64, bool)]) -> Vec<f64> {
+        let config = AccelerationConfig::default();
+        let start = Instant::now();
+        let mut accelerator = Accelerator::default();
+        turns
+            .iter()
+            .map(|&(ms, up)| accelerator.step(&config, up, start + Duration::from_millis(ms)))
+            .collect()
+    }
+
+    #[test]
+    fn starts_with_the_fine_step() {
+        assert_eq!(steps(&[(0, true)]), vec![1.0]);
+        assert_eq!(steps(&[(0, false)]), vec![1.0]);
+    }
+
+    #[test]
+    fn picks_the_quickest_level_covering_the_gap() {
+        let turns = [(0, true), (80, true), (330, true), (600, true), (650, true)];
+        assert_eq!(steps(&turns), vec![1.0, 10.0, 5.0, 1.0, 10.0]);
+    }
+
+    #[test]
+    fn starts_over_when_the_direction_changes() {
+        let turns = [
+            (0, true),
+            (50, true),
+            (100, false),
+            (150, false),
+            (200, true),
+        ];
+        assert_eq!(steps(&turns), vec![1.0, 10.0, 1.0, 10.0, 1.0]);
+    }
+}
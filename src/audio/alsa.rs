@@ -0,0 +1,202 @@
+use ::alsa::mixer::{Mixer, Selem, SelemChannelId, SelemId};
+use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
+
+use super::{AudioBackend, AudioError, Sink};
+
+/// Drives an ALSA simple mixer control directly, for systems without a sound server.
+///
+/// Raw control values are mapped linearly onto `0..=VOLUME_NORM`, so the same percent
+/// steps apply as with the sound server backends. Controls without a playback switch
+/// report themselves as never muted and ignore mute requests.
+pub struct AlsaBackend {
+    card: String,
+    control: String,
+    mixer: Mixer,
+}
+
+impl AlsaBackend {
+    pub fn open(card: &str, control: &str) -> Result<Self, String> {
+        let mixer = Mixer::new(card, false)
+            .map_err(|e| format!("Cannot open ALSA mixer {}: {}", card, e))?;
+        let backend = AlsaBackend {
+            card: card.to_string(),
+            control: control.to_string(),
+            mixer,
+        };
+        backend.selem()?;
+        Ok(backend)
+    }
+
+    fn selem(&self) -> Result<Selem<'_>, String> {
+        self.mixer
+            .find_selem(&SelemId::new(&self.control, 0))
+            .filter(|selem| selem.has_playback_volume())
+            .ok_or_else(|| {
+                format!(
+                    "ALSA card {} has no playback control named {}",
+                    self.card, self.control
+                )
+            })
+    }
+
+    /// Pulls in changes other programs made to the mixer since the last call.
+    fn refresh(&self) -> Result<(), String> {
+        self.mixer
+            .handle_events()
+            .map(|_| ())
+            .map_err(|e| format!("Cannot read ALSA mixer events: {}", e))
+    }
+
+    fn check_sink(&self, sink: &Sink) -> Result<(), AudioError> {
+        if sink.name == self.control {
+            Ok(())
+        } else {
+            Err(AudioError::NotFound(format!(
+                "No such ALSA control: {}",
+                sink.name
+            )))
+        }
+    }
+}
+
+fn playback_channels(selem: &Selem) -> Vec<SelemChannelId> {
+    if selem.is_playback_mono() {
+        return vec![SelemChannelId::mono()];
+    }
+    SelemChannelId::all()
+        .iter()
+        .copied()
+        .filter(|channel| *channel != SelemChannelId::Unknown && *channel != SelemChannelId::Last)
+        .filter(|channel| selem.has_playback_channel(*channel))
+        .collect()
+}
+
+fn raw_to_volume(raw: i64, (min, max): (i64, i64)) -> Volume {
+    if max <= min {
+        return Volume(0);
+    }
+    let fraction = (raw - min) as f64 / (max - min) as f64;
+    Volume((fraction * f64::from(VOLUME_NORM.0)) as u32)
+}
+
+fn volume_to_raw(volume: Volume, (min, max): (i64, i64)) -> i64 {
+    let fraction = f64::from(volume.0.min(VOLUME_NORM.0)) / f64::from(VOLUME_NORM.0);
+    min + (fraction * (max - min) as f64).round() as i64
+}
+
+impl AudioBackend for AlsaBackend {
+    fn default_sink(&mut self) -> Result<Sink, AudioError> {
+        Ok(Sink {
+            index: 0,
+            name: self.control.clone(),
+        })
+    }
+
+    fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError> {
+        let sink = Sink {
+            index: 0,
+            name: name.to_string(),
+        };
+        self.check_sink(&sink)?;
+        Ok(sink)
+    }
+
+    fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError> {
+        self.check_sink(sink)?;
+        self.refresh()?;
+        let selem = self.selem()?;
+        let range = selem.get_playback_volume_range();
+        let channels = playback_channels(&selem);
+
+        let mut volumes = ChannelVolumes::default();
+        volumes.set_len(channels.len() as u8);
+        for (volume, channel) in volumes.get_mut().iter_mut().zip(channels) {
+            let raw = selem
+                .get_playback_volume(channel)
+                .map_err(|e| format!("Cannot get ALSA volume: {}", e))?;
+            *volume = raw_to_volume(raw, range);
+        }
+        Ok(volumes)
+    }
+
+    fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError> {
+        self.check_sink(sink)?;
+        let selem = self.selem()?;
+        let range = selem.get_playback_volume_range();
+        for (volume, channel) in volumes.get().iter().zip(playback_channels(&selem)) {
+            selem
+                .set_playback_volume(channel, volume_to_raw(*volume, range))
+                .map_err(|e| format!("Cannot set ALSA volume: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError> {
+        self.check_sink(sink)?;
+        self.refresh()?;
+        let selem = self.selem()?;
+        if !selem.has_playback_switch() {
+            return Ok(false);
+        }
+        let switch = selem
+            .get_playback_switch(SelemChannelId::mono())
+            .map_err(|e| format!("Cannot get ALSA mute: {}", e))?;
+        Ok(switch == 0)
+    }
+
+    fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError> {
+        self.check_sink(sink)?;
+        let selem = self.selem()?;
+        if !selem.has_playback_switch() {
+            return Ok(());
+        }
+        selem
+            .set_playback_switch_all(if mute { 0 } else { 1 })
+            .map_err(|e| format!("Cannot set ALSA mute: {}", e))?;
+        Ok(())
+    }
+
+    /// There is only the one control, so it already is the default.
+    fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError> {
+        self.check_sink(sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANGE: (i64, i64) = (-20, 80);
+
+    #[test]
+    fn raw_values_map_linearly_onto_the_normal_range() {
+        assert_eq!(raw_to_volume(-20, RANGE), Volume(0));
+        assert_eq!(raw_to_volume(30, RANGE), Volume(VOLUME_NORM.0 / 2));
+        assert_eq!(raw_to_volume(80, RANGE), VOLUME_NORM);
+    }
+
+    #[test]
+    fn empty_ranges_read_as_silent() {
+        assert_eq!(raw_to_volume(5, (5, 5)), Volume(0));
+        assert_eq!(raw_to_volume(5, (10, 0)), Volume(0));
+    }
+
+    #[test]
+    fn volumes_map_back_to_raw_values() {
+        assert_eq!(volume_to_raw(Volume(0), RANGE), -20);
+        assert_eq!(volume_to_raw(Volume(VOLUME_NORM.0 / 2), RANGE), 30);
+        assert_eq!(volume_to_raw(VOLUME_NORM, RANGE), 80);
+    }
+
+    #[test]
+    fn volumes_above_normal_clamp_to_the_maximum() {
+        assert_eq!(volume_to_raw(Volume(VOLUME_NORM.0 * 3 / 2), RANGE), 80);
+    }
+
+    #[test]
+    fn raw_values_round_trip() {
+        for raw in RANGE.0..=RANGE.1 {
+            assert_eq!(volume_to_raw(raw_to_volume(raw, RANGE), RANGE), raw);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use libpulse_binding::volume::ChannelVolumes;
+
+use super::Sink;
+
+/// Volume and mute of a sink as last seen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkState {
+    pub sink: Sink,
+    pub volumes: ChannelVolumes,
+    pub mute: bool,
+}
+
+/// Change announced by the sound server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SinkEvent {
+    /// Server settings changed, possibly the default sink.
+    Server,
+    /// The sink with this index changed or went away.
+    Sink(u32),
+}
+
+/// The default sink with its volume and mute, fetched again only after the sound server
+/// announces a change that may concern it.
+#[derive(Debug, Default)]
+pub struct SinkCache {
+    default: Option<SinkState>,
+    /// What `changes` last returned, or what we set ourselves since.
+    reported: Option<SinkState>,
+}
+
+impl SinkCache {
+    pub fn handle(&mut self, event: SinkEvent) {
+        let stale = match (event, &self.default) {
+            (_, None) => false,
+            (SinkEvent::Server, Some(_)) => true,
+            (SinkEvent::Sink(index), Some(state)) => state.sink.index == index,
+        };
+        if stale {
+            self.default = None;
+        }
+    }
+
+    /// The default sink's state, from `fetch` if nothing is cached.
+    pub fn default_sink(
+        &mut self,
+        fetch: impl FnOnce() -> Result<SinkState, String>,
+    ) -> Result<&SinkState, String> {
+        if self.default.is_none() {
+            self.default = Some(fetch()?);
+        }
+        Ok(self.default.as_ref().unwrap())
+    }
+
+    /// The cached state of `sink`, if it is the default sink.
+    pub fn get(&self, sink: &Sink) -> Option<&SinkState> {
+        self.default.as_ref().filter(|state| state.sink == *sink)
+    }
+
+    /// Records a volume we set, so it is not reported as a change.
+    pub fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) {
+        self.record(sink, |state| state.volumes = *volumes);
+    }
+
+    /// Records a mute we set, so it is not reported as a change.
+    pub fn set_mute(&mut self, sink: &Sink, mute: bool) {
+        self.record(sink, |state| state.mute = mute);
+    }
+
+    fn record(&mut self, sink: &Sink, change: impl FnOnce(&mut SinkState)) {
+        if let Some(state) = self.default.as_mut().filter(|state| state.sink == *sink) {
+            change(state);
+            self.reported = Some(state.clone());
+        }
+    }
+
+    /// The default sink's state if it differs from what was last returned or set through us.
+    pub fn changes(
+        &mut self,
+        fetch: impl FnOnce() -> Result<SinkState, String>,
+    ) -> Result<Option<SinkState>, String> {
+        let state = self.default_sink(fetch)?.clone();
+        if self.reported.as_ref() == Some(&state) {
+            return Ok(None);
+        }
+        self.reported = Some(state.clone());
+        Ok(Some(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use libpulse_binding::volume::Volume;
+
+    use super::*;
+
+    fn state(index: u32, volume: u32, mute: bool) -> SinkState {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set(2, Volume(volume));
+        SinkState {
+            sink: Sink {
+                index,
+                name: format!("sink{}", index),
+            },
+            volumes,
+            mute,
+        }
+    }
+
+    /// A sound server whose default sink is `current`, counting fetches.
+    struct Server {
+        current: RefCell<SinkState>,
+        fetches: RefCell<usize>,
+    }
+
+    impl Server {
+        fn new(current: SinkState) -> Self {
+            Server {
+                current: RefCell::new(current),
+                fetches: RefCell::new(0),
+            }
+        }
+
+        fn fetch(&self) -> Result<SinkState, String> {
+            *self.fetches.borrow_mut() += 1;
+            Ok(self.current.borrow().clone())
+        }
+
+        fn fetches(&self) -> usize {
+            *self.fetches.borrow()
+        }
+    }
+
+    #[test]
+    fn fetches_again_only_after_a_change_to_the_default_sink() {
+        let server = Server::new(state(1, 100, false));
+        let mut cache = SinkCache::default();
+        cache.default_sink(|| server.fetch()).unwrap();
+        cache.default_sink(|| server.fetch()).unwrap();
+        assert_eq!(server.fetches(), 1);
+
+        cache.handle(SinkEvent::Sink(2));
+        cache.default_sink(|| server.fetch()).unwrap();
+        assert_eq!(server.fetches(), 1);
+
+        cache.handle(SinkEvent::Sink(1));
+        cache.default_sink(|| server.fetch()).unwrap();
+        assert_eq!(server.fetches(), 2);
+
+        *server.current.borrow_mut() = state(2, 100, false);
+        cache.handle(SinkEvent::Server);
+        let default = cache.default_sink(|| server.fetch()).unwrap();
+        assert_eq!(default.sink.index, 2);
+        assert_eq!(server.fetches(), 3);
+    }
+
+    #[test]
+    fn knows_only_the_default_sink() {
+        let server = Server::new(state(1, 100, false));
+        let mut cache = SinkCache::default();
+        assert_eq!(cache.get(&state(1, 0, false).sink), None);
+        cache.default_sink(|| server.fetch()).unwrap();
+        assert_eq!(
+            cache.get(&state(1, 0, false).sink),
+            Some(&state(1, 100, false))
+        );
+        assert_eq!(cache.get(&state(2, 0, false).sink), None);
+    }
+
+    #[test]
+    fn does_not_report_changes_made_through_it() {
+        let server = Server::new(state(1, 100, false));
+        let mut cache = SinkCache::default();
+        // the first call reports the initial state
+        assert_eq!(
+            cache.changes(|| server.fetch()),
+            Ok(Some(state(1, 100, false)))
+        );
+
+        let changed = state(1, 200, true);
+        cache.set_volume(&changed.sink, &changed.volumes);
+        cache.set_mute(&changed.sink, true);
+        *server.current.borrow_mut() = changed.clone();
+        cache.handle(SinkEvent::Sink(1));
+        assert_eq!(cache.changes(|| server.fetch()), Ok(None));
+        assert_eq!(cache.get(&changed.sink), Some(&changed));
+    }
+
+    #[test]
+    fn reports_changes_made_elsewhere() {
+        let server = Server::new(state(1, 100, false));
+        let mut cache = SinkCache::default();
+        cache.changes(|| server.fetch()).unwrap();
+        assert_eq!(cache.changes(|| server.fetch()), Ok(None));
+
+        *server.current.borrow_mut() = state(1, 150, false);
+        cache.handle(SinkEvent::Sink(1));
+        assert_eq!(
+            cache.changes(|| server.fetch()),
+            Ok(Some(state(1, 150, false)))
+        );
+        assert_eq!(cache.changes(|| server.fetch()), Ok(None));
+
+        *server.current.borrow_mut() = state(3, 150, false);
+        cache.handle(SinkEvent::Server);
+        assert_eq!(
+            cache.changes(|| server.fetch()),
+            Ok(Some(state(3, 150, false)))
+        );
+    }
+}
@@ -0,0 +1,505 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::fmt;
+#[cfg(test)]
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
+
+use super::cache::{SinkCache, SinkEvent, SinkState};
+#[cfg(test)]
+use super::Reconnecting;
+use super::{AudioBackend, AudioError, EqCurve, Sink};
+
+const SINK_INDEX: u32 = 0;
+const SINK_NAME: &str = "memory";
+
+/// A call that changed the memory sink, printed and, in tests, recorded by a `RecordingMixer`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    Volume(ChannelVolumes),
+    Mute(bool),
+    Equalizer(EqCurve),
+    DefaultSink,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Call::Volume(volumes) => write!(f, "volume {}", volumes.print()),
+            Call::Mute(mute) => write!(f, "mute {}", mute),
+            Call::Equalizer(curve) => write!(
+                f,
+                "equalizer bass {:+.1} dB, treble {:+.1} dB",
+                curve.bass, curve.treble
+            ),
+            Call::DefaultSink => write!(f, "default"),
+        }
+    }
+}
+
+/// A change another application makes to the memory sink, as written in replay files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutsideChange {
+    /// Sets every channel to this many percent.
+    Volume(f64),
+    Mute(bool),
+}
+
+impl OutsideChange {
+    /// Parses `volume PERCENT`, `mute` or `unmute`.
+    pub fn parse(change: &str) -> Result<Self, String> {
+        let words: Vec<&str> = change.split_whitespace().collect();
+        match words[..] {
+            ["volume", percent] => percent
+                .parse()
+                .map(OutsideChange::Volume)
+                .map_err(|e| format!("Invalid volume {}: {}", percent, e)),
+            ["mute"] => Ok(OutsideChange::Mute(true)),
+            ["unmute"] => Ok(OutsideChange::Mute(false)),
+            _ => Err(format!("Unknown sink change: {}", change)),
+        }
+    }
+}
+
+struct Mixer {
+    name: String,
+    volumes: ChannelVolumes,
+    mute: bool,
+    /// One per connection, told about every change.
+    subscribers: Vec<Sender<SinkEvent>>,
+}
+
+impl Mixer {
+    fn changed(&mut self) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(SinkEvent::Sink(SINK_INDEX)).is_ok());
+    }
+}
+
+/// The memory backend's single sink, playing the part of a sound server: clones share it, and
+/// every connection to it is told about changes made through the others.
+#[derive(Clone)]
+pub struct MemoryMixer {
+    mixer: Arc<Mutex<Mixer>>,
+}
+
+impl MemoryMixer {
+    /// A sink named `memory` with `channels` channels at the given raw volume.
+    pub fn new(volume: Volume, channels: u8) -> Self {
+        Self::named(SINK_NAME, volume, channels)
+    }
+
+    /// Like `new`, naming the sink `name`.
+    pub fn named(name: &str, volume: Volume, channels: u8) -> Self {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set(channels.into(), volume);
+        MemoryMixer {
+            mixer: Arc::new(Mutex::new(Mixer {
+                name: name.to_string(),
+                volumes,
+                mute: false,
+                subscribers: vec![],
+            })),
+        }
+    }
+
+    pub fn connect(&self) -> MemoryBackend {
+        let (subscriber, events) = mpsc::channel();
+        let mut mixer = self.mixer.lock().unwrap();
+        mixer.subscribers.push(subscriber);
+        MemoryBackend {
+            sink: Sink {
+                index: SINK_INDEX,
+                name: mixer.name.clone(),
+            },
+            mixer: self.clone(),
+            events,
+            cache: SinkCache::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn volumes(&self) -> ChannelVolumes {
+        self.mixer.lock().unwrap().volumes
+    }
+
+    #[cfg(test)]
+    pub fn mute(&self) -> bool {
+        self.mixer.lock().unwrap().mute
+    }
+
+    /// Makes the change as another application would, telling every connection about it.
+    pub fn change(&self, change: OutsideChange) {
+        let mut mixer = self.mixer.lock().unwrap();
+        match change {
+            OutsideChange::Volume(percent) => {
+                let volume = Volume((percent / 100.0 * f64::from(VOLUME_NORM.0)) as u32);
+                let channels = mixer.volumes.len();
+                mixer.volumes.set(channels.into(), volume);
+                println!(
+                    "{}: volume {} set elsewhere",
+                    mixer.name,
+                    mixer.volumes.print()
+                );
+            }
+            OutsideChange::Mute(mute) => {
+                mixer.mute = mute;
+                println!("{}: mute {} set elsewhere", mixer.name, mute);
+            }
+        }
+        mixer.changed();
+    }
+
+    /// Applies `call`, prints it and tells every connection.
+    fn apply(&self, call: Call, apply: impl FnOnce(&mut Mixer)) {
+        let mut mixer = self.mixer.lock().unwrap();
+        apply(&mut mixer);
+        println!("{}: {}", mixer.name, call);
+        mixer.changed();
+    }
+
+    fn fetch(&self, sink: &Sink) -> SinkState {
+        let mixer = self.mixer.lock().unwrap();
+        SinkState {
+            sink: sink.clone(),
+            volumes: mixer.volumes,
+            mute: mixer.mute,
+        }
+    }
+}
+
+/// Deterministic mixer kept entirely in memory; prints every change it receives.
+///
+/// Handy for dry runs and for replaying recorded reports without a sound server. Each
+/// connection caches the sink's state until told of a change, as the PulseAudio backend does.
+pub struct MemoryBackend {
+    sink: Sink,
+    mixer: MemoryMixer,
+    events: Receiver<SinkEvent>,
+    cache: SinkCache,
+}
+
+impl MemoryBackend {
+    fn check_sink(&self, sink: &Sink) -> Result<(), AudioError> {
+        if *sink == self.sink {
+            Ok(())
+        } else {
+            Err(AudioError::NotFound(format!("No such sink: {}", sink.name)))
+        }
+    }
+
+    fn state(&mut self) -> &SinkState {
+        for event in self.events.try_iter() {
+            self.cache.handle(event);
+        }
+        let (mixer, sink) = (&self.mixer, &self.sink);
+        // fetching from memory cannot fail
+        self.cache.default_sink(|| Ok(mixer.fetch(sink))).unwrap()
+    }
+}
+
+impl AudioBackend for MemoryBackend {
+    fn default_sink(&mut self) -> Result<Sink, AudioError> {
+        Ok(self.state().sink.clone())
+    }
+
+    fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError> {
+        if name == self.sink.name {
+            Ok(self.sink.clone())
+        } else {
+            Err(AudioError::NotFound(format!("No such sink: {}", name)))
+        }
+    }
+
+    fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError> {
+        self.check_sink(sink)?;
+        Ok(self.state().volumes)
+    }
+
+    fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError> {
+        self.check_sink(sink)?;
+        self.mixer
+            .apply(Call::Volume(*volumes), |mixer| mixer.volumes = *volumes);
+        self.cache.set_volume(sink, volumes);
+        Ok(())
+    }
+
+    fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError> {
+        self.check_sink(sink)?;
+        Ok(self.state().mute)
+    }
+
+    fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError> {
+        self.check_sink(sink)?;
+        self.mixer
+            .apply(Call::Mute(mute), |mixer| mixer.mute = mute);
+        self.cache.set_mute(sink, mute);
+        Ok(())
+    }
+
+    fn set_equalizer(&mut self, sink: &Sink, curve: &EqCurve) -> Result<(), AudioError> {
+        self.check_sink(sink)?;
+        self.mixer.apply(Call::Equalizer(*curve), |_| {});
+        Ok(())
+    }
+
+    fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError> {
+        self.check_sink(sink)?;
+        self.mixer.apply(Call::DefaultSink, |_| {});
+        Ok(())
+    }
+
+    fn changes(&mut self) -> Result<Option<SinkState>, AudioError> {
+        self.state();
+        let (mixer, sink) = (&self.mixer, &self.sink);
+        Ok(self.cache.changes(|| Ok(mixer.fetch(sink)))?)
+    }
+}
+
+/// A memory mixer whose connections record the calls that change it, so the effect of a
+/// sequence of actions can be checked, and can be made to fail.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct RecordingMixer {
+    mixer: MemoryMixer,
+    log: Arc<Mutex<CallLog>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct CallLog {
+    calls: Vec<Call>,
+    /// How many of the next calls fail.
+    failures: usize,
+}
+
+#[cfg(test)]
+impl RecordingMixer {
+    pub fn new(volume: Volume, channels: u8) -> Self {
+        Self::named(SINK_NAME, volume, channels)
+    }
+
+    pub fn named(name: &str, volume: Volume, channels: u8) -> Self {
+        RecordingMixer {
+            mixer: MemoryMixer::named(name, volume, channels),
+            log: Arc::default(),
+        }
+    }
+
+    /// The mixer recorded, for changes made without going through a connection.
+    pub fn memory(&self) -> MemoryMixer {
+        self.mixer.clone()
+    }
+
+    pub fn connect(&self) -> RecordingBackend {
+        RecordingBackend {
+            backend: self.mixer.connect(),
+            log: self.log.clone(),
+        }
+    }
+
+    pub fn change(&self, change: OutsideChange) {
+        self.mixer.change(change);
+    }
+
+    pub fn volumes(&self) -> ChannelVolumes {
+        self.mixer.volumes()
+    }
+
+    pub fn mute(&self) -> bool {
+        self.mixer.mute()
+    }
+
+    /// The calls recorded since the last time, oldest first.
+    pub fn take_calls(&self) -> Vec<Call> {
+        std::mem::take(&mut self.log.lock().unwrap().calls)
+    }
+
+    /// Makes the next `calls` calls through any connection fail, as if the server went away.
+    pub fn fail_calls(&self, calls: usize) {
+        self.log.lock().unwrap().failures = calls;
+    }
+
+    /// A connection that reconnects to this mixer after a lost connection, and how often it
+    /// connected.
+    pub fn reconnecting(&self) -> (Reconnecting, Rc<Cell<usize>>) {
+        let connects = Rc::new(Cell::new(0));
+        let backend = {
+            let (mixer, connects) = (self.clone(), connects.clone());
+            Reconnecting::new(move || {
+                connects.set(connects.get() + 1);
+                Ok(Box::new(mixer.connect()))
+            })
+            .unwrap()
+        };
+        (backend, connects)
+    }
+}
+
+/// A connection to a `RecordingMixer`.
+#[cfg(test)]
+pub struct RecordingBackend {
+    backend: MemoryBackend,
+    log: Arc<Mutex<CallLog>>,
+}
+
+#[cfg(test)]
+impl RecordingBackend {
+    /// Fails the call if failures were asked for.
+    fn check_failure(&self) -> Result<(), AudioError> {
+        let mut log = self.log.lock().unwrap();
+        if log.failures > 0 {
+            log.failures -= 1;
+            return Err(AudioError::Disconnected(String::from("Injected failure")));
+        }
+        Ok(())
+    }
+
+    /// Records `call` once it succeeded.
+    fn record(&self, call: Call, result: Result<(), AudioError>) -> Result<(), AudioError> {
+        if result.is_ok() {
+            self.log.lock().unwrap().calls.push(call);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+impl AudioBackend for RecordingBackend {
+    fn default_sink(&mut self) -> Result<Sink, AudioError> {
+        self.check_failure()?;
+        self.backend.default_sink()
+    }
+
+    fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError> {
+        self.check_failure()?;
+        self.backend.find_sink(name)
+    }
+
+    fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError> {
+        self.check_failure()?;
+        self.backend.volume(sink)
+    }
+
+    fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError> {
+        self.check_failure()?;
+        let result = self.backend.set_volume(sink, volumes);
+        self.record(Call::Volume(*volumes), result)
+    }
+
+    fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError> {
+        self.check_failure()?;
+        self.backend.mute(sink)
+    }
+
+    fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError> {
+        self.check_failure()?;
+        let result = self.backend.set_mute(sink, mute);
+        self.record(Call::Mute(mute), result)
+    }
+
+    fn set_equalizer(&mut self, sink: &Sink, curve: &EqCurve) -> Result<(), AudioError> {
+        self.check_failure()?;
+        let result = self.backend.set_equalizer(sink, curve);
+        self.record(Call::Equalizer(*curve), result)
+    }
+
+    fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError> {
+        self.check_failure()?;
+        let result = self.backend.set_default_sink(sink);
+        self.record(Call::DefaultSink, result)
+    }
+
+    fn changes(&mut self) -> Result<Option<SinkState>, AudioError> {
+        self.check_failure()?;
+        self.backend.changes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(percent: u32, mute: bool) -> SinkState {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set(2, Volume(percent * VOLUME_NORM.0 / 100));
+        SinkState {
+            sink: Sink {
+                index: SINK_INDEX,
+                name: SINK_NAME.to_string(),
+            },
+            volumes,
+            mute,
+        }
+    }
+
+    #[test]
+    fn reports_an_outside_change_once() {
+        let mixer = MemoryMixer::new(Volume(VOLUME_NORM.0 / 2), 2);
+        let mut backend = mixer.connect();
+        assert_eq!(backend.changes(), Ok(Some(state(50, false))));
+        assert_eq!(backend.changes(), Ok(None));
+
+        mixer.change(OutsideChange::Volume(25.0));
+        assert_eq!(backend.changes(), Ok(Some(state(25, false))));
+        assert_eq!(backend.changes(), Ok(None));
+
+        mixer.change(OutsideChange::Mute(true));
+        assert_eq!(backend.changes(), Ok(Some(state(25, true))));
+        assert_eq!(backend.changes(), Ok(None));
+    }
+
+    #[test]
+    fn reads_the_volume_changed_elsewhere() {
+        let mixer = MemoryMixer::new(Volume(VOLUME_NORM.0 / 2), 2);
+        let mut backend = mixer.connect();
+        let sink = backend.default_sink().unwrap();
+        assert_eq!(backend.volume(&sink), Ok(state(50, false).volumes));
+
+        mixer.change(OutsideChange::Volume(25.0));
+        mixer.change(OutsideChange::Mute(true));
+        assert_eq!(backend.volume(&sink), Ok(state(25, true).volumes));
+        assert_eq!(backend.mute(&sink), Ok(true));
+    }
+
+    #[test]
+    fn reports_changes_made_through_other_connections_only() {
+        let mixer = MemoryMixer::new(Volume(VOLUME_NORM.0 / 2), 2);
+        let mut ours = mixer.connect();
+        let mut theirs = mixer.connect();
+        ours.changes().unwrap();
+        theirs.changes().unwrap();
+
+        let sink = ours.default_sink().unwrap();
+        ours.set_volume(&sink, &state(25, false).volumes).unwrap();
+        ours.set_mute(&sink, true).unwrap();
+        assert_eq!(ours.changes(), Ok(None));
+        assert_eq!(theirs.changes(), Ok(Some(state(25, true))));
+        assert_eq!(theirs.changes(), Ok(None));
+    }
+
+    #[test]
+    fn parses_outside_changes() {
+        assert_eq!(
+            OutsideChange::parse("volume 30"),
+            Ok(OutsideChange::Volume(30.0))
+        );
+        assert_eq!(
+            OutsideChange::parse(" volume  12.5 "),
+            Ok(OutsideChange::Volume(12.5))
+        );
+        assert_eq!(OutsideChange::parse("mute"), Ok(OutsideChange::Mute(true)));
+        assert_eq!(
+            OutsideChange::parse("unmute"),
+            Ok(OutsideChange::Mute(false))
+        );
+        assert_eq!(
+            OutsideChange::parse("volume"),
+            Err(String::from("Unknown sink change: volume"))
+        );
+        assert!(OutsideChange::parse("volume loud").is_err());
+    }
+}
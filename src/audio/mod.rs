@@ -0,0 +1,161 @@
+use std::fmt;
+use std::sync::Arc;
+
+use libpulse_binding::channelmap::{Map, MapDef};
+use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
+
+use crate::config::BackendConfig;
+
+#[cfg(feature = "alsa")]
+pub use self::alsa::AlsaBackend;
+#[cfg(feature = "pipewire")]
+pub use self::pipewire::PipeWireBackend;
+pub use cache::SinkState;
+#[cfg(test)]
+pub use memory::{Call, RecordingMixer};
+pub use memory::{MemoryMixer, OutsideChange};
+pub use pulse::PulseBackend;
+pub use reconnect::Reconnecting;
+
+#[cfg(feature = "alsa")]
+mod alsa;
+mod cache;
+mod memory;
+#[cfg(feature = "pipewire")]
+mod pipewire;
+mod pulse;
+mod reconnect;
+
+/// Output device the driver adjusts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sink {
+    pub index: u32,
+    pub name: String,
+}
+
+/// Shelving gains of a software equalizer, in dB.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EqCurve {
+    pub bass: f64,
+    pub treble: f64,
+}
+
+/// Why a call to an audio backend failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioError {
+    /// The sound server went away or could not be reached; a new connection may help.
+    Disconnected(String),
+    /// The sink asked for does not exist.
+    NotFound(String),
+    /// The backend or sink cannot do what was asked.
+    Unsupported(String),
+}
+
+impl AudioError {
+    /// Whether the connection is worth making again.
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, AudioError::Disconnected(_))
+    }
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Disconnected(message)
+            | AudioError::NotFound(message)
+            | AudioError::Unsupported(message) => f.write_str(message),
+        }
+    }
+}
+
+/// Failures a backend does not tell apart are taken for a lost connection.
+impl From<String> for AudioError {
+    fn from(message: String) -> Self {
+        AudioError::Disconnected(message)
+    }
+}
+
+/// Sound system the volume knob is wired to.
+pub trait AudioBackend {
+    fn default_sink(&mut self) -> Result<Sink, AudioError>;
+    fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError>;
+    fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError>;
+    fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError>;
+    fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError>;
+    fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError>;
+    fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError>;
+
+    /// Positions of the sink's channels, in the order of its volumes; guessed from the
+    /// channel count in ALSA's order unless the backend knows them.
+    fn channel_map(&mut self, sink: &Sink) -> Result<Map, AudioError> {
+        let channels = self.volume(sink)?.len();
+        let mut map = Map::default();
+        map.init_auto(channels.into(), MapDef::ALSA)
+            .ok_or_else(|| {
+                AudioError::Unsupported(format!("No channel map for {} channels", channels))
+            })?;
+        Ok(map)
+    }
+
+    /// Filters everything played on `sink` through an equalizer with the given curve,
+    /// replacing the previous one.
+    fn set_equalizer(&mut self, sink: &Sink, _curve: &EqCurve) -> Result<(), AudioError> {
+        Err(AudioError::Unsupported(format!(
+            "No equalizer available for {}",
+            sink.name
+        )))
+    }
+
+    /// The default sink's state, if other applications changed it since the last call; the
+    /// first call reports the initial state. Backends that cannot follow the sound server's
+    /// changes never report any.
+    fn changes(&mut self) -> Result<Option<SinkState>, AudioError> {
+        Ok(None)
+    }
+}
+
+/// Average volume over all channels, in percent of the normal volume.
+pub fn percent(volumes: &ChannelVolumes) -> f64 {
+    f64::from(volumes.avg().0) / f64::from(VOLUME_NORM.0) * 100.0
+}
+
+/// Whether the named backend implements `set_equalizer`.
+pub fn has_equalizer(name: &str) -> bool {
+    matches!(name, "pulse" | "memory")
+}
+
+/// Opens a connection to the backend named in the configuration.
+pub type Connect =
+    Arc<dyn Fn(&BackendConfig) -> Result<Box<dyn AudioBackend>, String> + Send + Sync>;
+
+/// Connects like `open`; every connection to the `memory` backend goes to `mixer`, so device
+/// handlers, the D-Bus worker and replayed sink changes all see one sink.
+pub fn connector(mixer: MemoryMixer) -> Connect {
+    Arc::new(move |config| open(config, &mixer))
+}
+
+/// Connects to the backend named in the configuration, or to `mixer` for the `memory`
+/// backend, reconnecting whenever the connection is lost.
+pub fn open(config: &BackendConfig, mixer: &MemoryMixer) -> Result<Box<dyn AudioBackend>, String> {
+    Ok(Box::new(Reconnecting::open(config, mixer)?))
+}
+
+/// A mixer for the `memory` backend, at half the normal volume.
+pub fn memory_mixer(config: &BackendConfig) -> MemoryMixer {
+    MemoryMixer::new(Volume(VOLUME_NORM.0 / 2), config.memory_channels)
+}
+
+fn connect(config: &BackendConfig, mixer: &MemoryMixer) -> Result<Box<dyn AudioBackend>, String> {
+    match config.name.as_str() {
+        "pulse" => Ok(Box::new(PulseBackend::connect()?)),
+        #[cfg(feature = "pipewire")]
+        "pipewire" => Ok(Box::new(PipeWireBackend::connect()?)),
+        #[cfg(feature = "alsa")]
+        "alsa" => Ok(Box::new(AlsaBackend::open(
+            &config.alsa_card,
+            &config.alsa_control,
+        )?)),
+        "memory" => Ok(Box::new(mixer.connect())),
+        other => Err(format!("Unknown backend: {}", other)),
+    }
+}
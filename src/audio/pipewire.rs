@@ -0,0 +1,718 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::sync::Once;
+use std::time::Duration;
+
+use ::pipewire as pw;
+use libpulse_binding::sample::CHANNELS_MAX;
+use libpulse_binding::volume::{ChannelVolumes, Volume, VolumeLinear};
+use pw::device::{Device, DeviceListener};
+use pw::metadata::{Metadata, MetadataListener};
+use pw::node::{Node, NodeListener};
+use pw::registry::Registry;
+use pw::spa::param::ParamType;
+use pw::spa::pod::deserialize::PodDeserializer;
+use pw::spa::pod::serialize::PodSerializer;
+use pw::spa::pod::{Object, Pod, Property, Value, ValueArray};
+use pw::types::ObjectType;
+
+use super::{AudioBackend, AudioError, Sink};
+
+const DEFAULT_SINK_KEY: &str = "default.audio.sink";
+const CONFIGURED_DEFAULT_SINK_KEY: &str = "default.configured.audio.sink";
+/// How long to wait for the server to answer a round trip.
+const ROUNDTRIP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// PipeWire is initialized once per process, however often the backend reconnects.
+static INIT: Once = Once::new();
+
+/// Volume and mute as last reported by a node's `Props` param.
+#[derive(Debug, Default, Clone)]
+struct NodeProps {
+    channel_volumes: Vec<f32>,
+    mute: bool,
+}
+
+impl NodeProps {
+    /// The channel volumes in PulseAudio's terms; there are none until the node reports its
+    /// `Props`.
+    fn volumes(&self) -> Option<ChannelVolumes> {
+        let channels = self.channel_volumes.len();
+        if channels == 0 || channels > CHANNELS_MAX {
+            return None;
+        }
+        let mut volumes = ChannelVolumes::default();
+        volumes.set_len(channels as u8);
+        for (volume, linear) in volumes.get_mut().iter_mut().zip(&self.channel_volumes) {
+            *volume = Volume::from(VolumeLinear(f64::from(*linear)));
+        }
+        Some(volumes)
+    }
+}
+
+struct AudioNode {
+    name: String,
+    /// The device the node plays through, and the index of its route on that device.
+    device: Option<(u32, i32)>,
+    proxy: Node,
+    props: NodeProps,
+    _listener: NodeListener,
+}
+
+/// A device's active route, as reported by its `Route` param.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Route {
+    index: i32,
+    device: i32,
+}
+
+struct AudioDevice {
+    proxy: Device,
+    /// Route indexes by the route device index nodes refer to.
+    routes: HashMap<i32, i32>,
+    _listener: DeviceListener,
+}
+
+#[derive(Default)]
+struct State {
+    nodes: HashMap<u32, AudioNode>,
+    devices: HashMap<u32, AudioDevice>,
+    default_sink_name: Option<String>,
+    metadata: Option<(Metadata, MetadataListener)>,
+}
+
+/// Talks to PipeWire directly. Volume and mute are set on the active route of the sink's device,
+/// as `wpctl` does, so the session manager saves them; sinks without a device route have the
+/// `Props` of their node adjusted instead.
+pub struct PipeWireBackend {
+    mainloop: pw::main_loop::MainLoop,
+    core: pw::core::Core,
+    state: Rc<RefCell<State>>,
+    _context: pw::context::Context,
+    _registry: Rc<Registry>,
+    _registry_listener: pw::registry::Listener,
+}
+
+impl PipeWireBackend {
+    pub fn connect() -> Result<Self, String> {
+        INIT.call_once(pw::init);
+        let mainloop = pw::main_loop::MainLoop::new(None)
+            .map_err(|e| format!("Cannot create PipeWire main loop: {}", e))?;
+        let context = pw::context::Context::new(&mainloop)
+            .map_err(|e| format!("Cannot create PipeWire context: {}", e))?;
+        let core = context
+            .connect(None)
+            .map_err(|e| format!("Cannot connect to PipeWire: {}", e))?;
+        let registry = Rc::new(
+            core.get_registry()
+                .map_err(|e| format!("Cannot get PipeWire registry: {}", e))?,
+        );
+
+        let state = Rc::new(RefCell::new(State::default()));
+        let registry_listener = {
+            let registry_weak = Rc::downgrade(&registry);
+            let state_global = state.clone();
+            let state_remove = state.clone();
+            registry
+                .add_listener_local()
+                .global(move |global| {
+                    let registry = match registry_weak.upgrade() {
+                        Some(registry) => registry,
+                        None => return,
+                    };
+                    let props = match global.props {
+                        Some(props) => props,
+                        None => return,
+                    };
+                    match global.type_ {
+                        ObjectType::Node if props.get("media.class") == Some("Audio/Sink") => {
+                            let name = props.get("node.name").unwrap_or_default().to_string();
+                            let device_id = props.get("device.id").and_then(|id| id.parse().ok());
+                            let route_device = props
+                                .get("card.profile.device")
+                                .and_then(|device| device.parse().ok());
+                            let device = device_id.zip(route_device);
+                            if let Ok(node) = registry.bind::<Node, _>(global) {
+                                bind_node(&state_global, global.id, name, device, node);
+                            }
+                        }
+                        ObjectType::Device if props.get("media.class") == Some("Audio/Device") => {
+                            if let Ok(device) = registry.bind::<Device, _>(global) {
+                                bind_device(&state_global, global.id, device);
+                            }
+                        }
+                        ObjectType::Metadata if props.get("metadata.name") == Some("default") => {
+                            if let Ok(metadata) = registry.bind::<Metadata, _>(global) {
+                                bind_metadata(&state_global, metadata);
+                            }
+                        }
+                        _ => {}
+                    }
+                })
+                .global_remove(move |id| {
+                    let mut state = state_remove.borrow_mut();
+                    state.nodes.remove(&id);
+                    state.devices.remove(&id);
+                })
+                .register()
+        };
+
+        let backend = PipeWireBackend {
+            mainloop,
+            core,
+            state,
+            _context: context,
+            _registry: registry,
+            _registry_listener: registry_listener,
+        };
+        // first round trip delivers the globals, second one their params and metadata
+        backend.roundtrip()?;
+        backend.roundtrip()?;
+        Ok(backend)
+    }
+
+    /// Runs the main loop until the server has processed everything sent so far. Fails if the
+    /// server reports an error, e.g. because it went away, or does not answer in time.
+    fn roundtrip(&self) -> Result<(), String> {
+        let outcome: Rc<RefCell<Option<Result<(), String>>>> = Rc::new(RefCell::new(None));
+        // the first outcome wins; the main loop stops on it
+        let settle = {
+            let outcome = outcome.clone();
+            let mainloop = self.mainloop.clone();
+            Rc::new(move |result: Result<(), String>| {
+                outcome.borrow_mut().get_or_insert(result);
+                mainloop.quit();
+            })
+        };
+
+        let pending = self
+            .core
+            .sync(0)
+            .map_err(|e| format!("Cannot sync with PipeWire: {}", e))?;
+        let _listener = {
+            let settle_done = settle.clone();
+            let settle_error = settle.clone();
+            self.core
+                .add_listener_local()
+                .done(move |id, seq| {
+                    if id == pw::core::PW_ID_CORE && seq == pending {
+                        settle_done(Ok(()));
+                    }
+                })
+                .error(move |id, _seq, res, message| {
+                    settle_error(Err(format!(
+                        "PipeWire error on {}: {} ({})",
+                        id, message, res
+                    )));
+                })
+                .register()
+        };
+        let timer = self.mainloop.loop_().add_timer(move |_| {
+            settle(Err(String::from("PipeWire did not answer in time")));
+        });
+        timer
+            .update_timer(Some(ROUNDTRIP_TIMEOUT), None)
+            .into_result()
+            .map_err(|e| format!("Cannot start PipeWire timer: {}", e))?;
+
+        loop {
+            if let Some(result) = outcome.borrow_mut().take() {
+                return result;
+            }
+            self.mainloop.run();
+        }
+    }
+
+    /// The sink's props once it has reported its volumes; a node bound during the last round
+    /// trip reports them on the next one.
+    fn props(&self, sink: &Sink) -> Result<NodeProps, String> {
+        for _ in 0..2 {
+            self.roundtrip()?;
+            let props = self
+                .state
+                .borrow()
+                .nodes
+                .get(&sink.index)
+                .map(|node| node.props.clone())
+                .ok_or_else(|| format!("No such PipeWire node: {}", sink.index))?;
+            if props.volumes().is_some() {
+                return Ok(props);
+            }
+        }
+        Err(format!(
+            "PipeWire sink {} has not reported its volume",
+            sink.name
+        ))
+    }
+
+    /// Sets `property` on the sink's device route, or on the node itself if it has none.
+    fn set_props(&self, sink: &Sink, property: Property) -> Result<(), String> {
+        {
+            let state = self.state.borrow();
+            let node = state
+                .nodes
+                .get(&sink.index)
+                .ok_or_else(|| format!("No such PipeWire node: {}", sink.index))?;
+            let route = node.device.and_then(|(id, device)| {
+                let owner = state.devices.get(&id)?;
+                let index = *owner.routes.get(&device)?;
+                Some((owner, Route { index, device }))
+            });
+            match route {
+                Some((owner, route)) => {
+                    let bytes = serialize_route(route, vec![property])?;
+                    let pod = Pod::from_bytes(&bytes).ok_or("Cannot build PipeWire route pod")?;
+                    owner.proxy.set_param(ParamType::Route, 0, pod);
+                }
+                None => {
+                    let bytes = serialize_props(vec![property])?;
+                    let pod = Pod::from_bytes(&bytes).ok_or("Cannot build PipeWire props pod")?;
+                    node.proxy.set_param(ParamType::Props, 0, pod);
+                }
+            }
+        }
+        self.roundtrip()
+    }
+}
+
+fn bind_node(
+    state: &Rc<RefCell<State>>,
+    id: u32,
+    name: String,
+    device: Option<(u32, i32)>,
+    proxy: Node,
+) {
+    let state_weak = Rc::downgrade(state);
+    let listener = proxy
+        .add_listener_local()
+        .param(move |_seq, param_type, _index, _next, param| {
+            if param_type != ParamType::Props {
+                return;
+            }
+            let (state, param) = match (state_weak.upgrade(), param) {
+                (Some(state), Some(param)) => (state, param),
+                _ => return,
+            };
+            if let Some(node) = state.borrow_mut().nodes.get_mut(&id) {
+                parse_props(param, &mut node.props);
+            }
+        })
+        .register();
+    proxy.subscribe_params(&[ParamType::Props]);
+
+    state.borrow_mut().nodes.insert(
+        id,
+        AudioNode {
+            name,
+            device,
+            proxy,
+            props: NodeProps::default(),
+            _listener: listener,
+        },
+    );
+}
+
+fn bind_device(state: &Rc<RefCell<State>>, id: u32, proxy: Device) {
+    let state_weak = Rc::downgrade(state);
+    let listener = proxy
+        .add_listener_local()
+        .param(move |_seq, param_type, _index, _next, param| {
+            if param_type != ParamType::Route {
+                return;
+            }
+            let (state, route) = match (state_weak.upgrade(), param.and_then(parse_route)) {
+                (Some(state), Some(route)) => (state, route),
+                _ => return,
+            };
+            if let Some(device) = state.borrow_mut().devices.get_mut(&id) {
+                device.routes.insert(route.device, route.index);
+            }
+        })
+        .register();
+    proxy.subscribe_params(&[ParamType::Route]);
+
+    state.borrow_mut().devices.insert(
+        id,
+        AudioDevice {
+            proxy,
+            routes: HashMap::new(),
+            _listener: listener,
+        },
+    );
+}
+
+fn bind_metadata(state: &Rc<RefCell<State>>, metadata: Metadata) {
+    let state_weak = Rc::downgrade(state);
+    let listener = metadata
+        .add_listener_local()
+        .property(move |_subject, key, _type, value| {
+            if let (Some(state), Some(DEFAULT_SINK_KEY)) = (state_weak.upgrade(), key) {
+                state.borrow_mut().default_sink_name = value.and_then(parse_metadata_name);
+            }
+            0
+        })
+        .register();
+    state.borrow_mut().metadata = Some((metadata, listener));
+}
+
+/// Extracts the node name from a metadata value like `{ "name": "alsa_output.usb-Razer" }`,
+/// which is a JSON object that may have other fields too.
+fn parse_metadata_name(value: &str) -> Option<String> {
+    let mut rest = value;
+    while let Some(start) = rest.find('"') {
+        let (string, after) = parse_json_string(&rest[start..])?;
+        let after = after.trim_start();
+        match after.strip_prefix(':') {
+            Some(field) if string == "name" => {
+                return parse_json_string(field.trim_start()).map(|(name, _)| name)
+            }
+            _ => rest = after,
+        }
+    }
+    None
+}
+
+/// Unescapes the JSON string `text` starts with, returning it and the text after it.
+fn parse_json_string(text: &str) -> Option<(String, &str)> {
+    let body = text.strip_prefix('"')?;
+    let mut chars = body.char_indices();
+    let mut string = String::new();
+    while let Some((at, c)) = chars.next() {
+        let c = match c {
+            '"' => return Some((string, &body[at + 1..])),
+            '\\' => match chars.next()?.1 {
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let hex = (0..4)
+                        .map(|_| chars.next().map(|(_, digit)| digit))
+                        .collect::<Option<String>>()?;
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                escaped => escaped,
+            },
+            c => c,
+        };
+        string.push(c);
+    }
+    None
+}
+
+/// `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                json.push('\\');
+                json.push(c);
+            }
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn props_object(properties: Vec<Property>) -> Object {
+    Object {
+        type_: pw::spa::sys::SPA_TYPE_OBJECT_Props,
+        id: pw::spa::sys::SPA_PARAM_Props,
+        properties,
+    }
+}
+
+fn serialize(object: Object) -> Result<Vec<u8>, String> {
+    Ok(
+        PodSerializer::serialize(Cursor::new(Vec::new()), &Value::Object(object))
+            .map_err(|e| format!("Cannot serialize PipeWire param: {:?}", e))?
+            .0
+            .into_inner(),
+    )
+}
+
+/// A `Props` param setting `properties`, as pod bytes.
+fn serialize_props(properties: Vec<Property>) -> Result<Vec<u8>, String> {
+    serialize(props_object(properties))
+}
+
+/// A `Route` param setting `properties` on `route` and asking the session manager to save them,
+/// as pod bytes.
+fn serialize_route(route: Route, properties: Vec<Property>) -> Result<Vec<u8>, String> {
+    serialize(Object {
+        type_: pw::spa::sys::SPA_TYPE_OBJECT_ParamRoute,
+        id: pw::spa::sys::SPA_PARAM_Route,
+        properties: vec![
+            Property::new(pw::spa::sys::SPA_PARAM_ROUTE_index, Value::Int(route.index)),
+            Property::new(
+                pw::spa::sys::SPA_PARAM_ROUTE_device,
+                Value::Int(route.device),
+            ),
+            Property::new(
+                pw::spa::sys::SPA_PARAM_ROUTE_props,
+                Value::Object(props_object(properties)),
+            ),
+            Property::new(pw::spa::sys::SPA_PARAM_ROUTE_save, Value::Bool(true)),
+        ],
+    })
+}
+
+fn deserialize(param: &Pod) -> Option<Object> {
+    match PodDeserializer::deserialize_any_from(param.as_bytes()) {
+        Ok((_, Value::Object(object))) => Some(object),
+        _ => None,
+    }
+}
+
+/// The route a `Route` param describes; it needs both its index and its device.
+fn parse_route(param: &Pod) -> Option<Route> {
+    let (mut index, mut device) = (None, None);
+    for property in deserialize(param)?.properties {
+        match (property.key, property.value) {
+            (pw::spa::sys::SPA_PARAM_ROUTE_index, Value::Int(value)) => index = Some(value),
+            (pw::spa::sys::SPA_PARAM_ROUTE_device, Value::Int(value)) => device = Some(value),
+            _ => {}
+        }
+    }
+    Some(Route {
+        index: index?,
+        device: device?,
+    })
+}
+
+fn parse_props(param: &Pod, props: &mut NodeProps) {
+    if let Some(object) = deserialize(param) {
+        read_props(object, props);
+    }
+}
+
+fn read_props(object: Object, props: &mut NodeProps) {
+    for property in object.properties {
+        match (property.key, property.value) {
+            (pw::spa::sys::SPA_PROP_channelVolumes, Value::ValueArray(ValueArray::Float(v))) => {
+                props.channel_volumes = v;
+            }
+            (pw::spa::sys::SPA_PROP_mute, Value::Bool(mute)) => props.mute = mute,
+            _ => {}
+        }
+    }
+}
+
+impl AudioBackend for PipeWireBackend {
+    fn default_sink(&mut self) -> Result<Sink, AudioError> {
+        self.roundtrip()?;
+        let default_name = self
+            .state
+            .borrow()
+            .default_sink_name
+            .clone()
+            .ok_or_else(|| {
+                AudioError::NotFound(String::from("PipeWire has no default audio sink"))
+            })?;
+        self.find_sink(&default_name)
+    }
+
+    fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError> {
+        self.state
+            .borrow()
+            .nodes
+            .iter()
+            .find(|(_, node)| node.name == name)
+            .map(|(id, node)| Sink {
+                index: *id,
+                name: node.name.clone(),
+            })
+            .ok_or_else(|| AudioError::NotFound(format!("PipeWire sink {} not found", name)))
+    }
+
+    fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError> {
+        // `props` only returns props with volumes
+        Ok(self.props(sink)?.volumes().unwrap())
+    }
+
+    fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError> {
+        let linear = volumes
+            .get()
+            .iter()
+            .map(|volume| VolumeLinear::from(*volume).0 as f32)
+            .collect();
+        self.set_props(
+            sink,
+            Property::new(
+                pw::spa::sys::SPA_PROP_channelVolumes,
+                Value::ValueArray(ValueArray::Float(linear)),
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError> {
+        Ok(self.props(sink)?.mute)
+    }
+
+    fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError> {
+        self.set_props(
+            sink,
+            Property::new(pw::spa::sys::SPA_PROP_mute, Value::Bool(mute)),
+        )?;
+        Ok(())
+    }
+
+    /// Sets the user's configured default, as `wpctl set-default` does.
+    fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError> {
+        {
+            let state = self.state.borrow();
+            let (metadata, _) = state.metadata.as_ref().ok_or_else(|| {
+                AudioError::Unsupported(String::from("PipeWire has no default metadata"))
+            })?;
+            let value = format!("{{ \"name\": {} }}", json_string(&sink.name));
+            metadata.set_property(
+                0,
+                CONFIGURED_DEFAULT_SINK_KEY,
+                Some("Spa:String:JSON"),
+                Some(&value),
+            );
+        }
+        self.roundtrip()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_name_from_metadata() {
+        assert_eq!(
+            parse_metadata_name(r#"{ "name": "alsa_output.usb-Razer" }"#).as_deref(),
+            Some("alsa_output.usb-Razer")
+        );
+        assert_eq!(
+            parse_metadata_name(r#"{"name":"speakers"}"#).as_deref(),
+            Some("speakers")
+        );
+        assert_eq!(
+            parse_metadata_name(r#"{ "nick" : "Name", "name" :  "speakers", "x": 1 }"#).as_deref(),
+            Some("speakers")
+        );
+        assert_eq!(parse_metadata_name(r#"{ "nick": "speakers" }"#), None);
+        assert_eq!(parse_metadata_name(r#"{ "name": "cut off }"#), None);
+        assert_eq!(parse_metadata_name(""), None);
+    }
+
+    #[test]
+    fn unescapes_names_from_metadata() {
+        assert_eq!(
+            parse_metadata_name(r#"{ "name": "the \"good\" one\\" }"#).as_deref(),
+            Some(r#"the "good" one\"#)
+        );
+        // a quoted "name" inside another value is not the key
+        assert_eq!(
+            parse_metadata_name(r#"{ "nick": "\"name\": \"x\"", "name": "speakers\u00e9" }"#)
+                .as_deref(),
+            Some("speakers\u{e9}")
+        );
+    }
+
+    #[test]
+    fn writes_names_that_read_back() {
+        for name in ["speakers", r#"the "good" one\"#, "tab\there"] {
+            let value = format!("{{ \"name\": {} }}", json_string(name));
+            assert_eq!(
+                parse_metadata_name(&value).as_deref(),
+                Some(name),
+                "{}",
+                value
+            );
+        }
+    }
+
+    fn parsed(properties: Vec<Property>) -> NodeProps {
+        let bytes = serialize_props(properties).unwrap();
+        let mut props = NodeProps::default();
+        parse_props(Pod::from_bytes(&bytes).unwrap(), &mut props);
+        props
+    }
+
+    #[test]
+    fn reads_volumes_and_mute_from_props() {
+        let props = parsed(vec![
+            Property::new(
+                pw::spa::sys::SPA_PROP_channelVolumes,
+                Value::ValueArray(ValueArray::Float(vec![1.0, 0.5])),
+            ),
+            Property::new(pw::spa::sys::SPA_PROP_mute, Value::Bool(true)),
+            Property::new(pw::spa::sys::SPA_PROP_volume, Value::Float(0.3)),
+        ]);
+        assert_eq!(props.channel_volumes, vec![1.0, 0.5]);
+        assert!(props.mute);
+        let volumes = props.volumes().unwrap();
+        assert_eq!(volumes.len(), 2);
+        assert_eq!(volumes.get()[0], Volume::from(VolumeLinear(1.0)));
+        assert_eq!(volumes.get()[1], Volume::from(VolumeLinear(0.5)));
+    }
+
+    #[test]
+    fn writes_volumes_to_the_route_they_read_back_from() {
+        let route = Route {
+            index: 3,
+            device: 7,
+        };
+        let bytes = serialize_route(
+            route,
+            vec![Property::new(
+                pw::spa::sys::SPA_PROP_channelVolumes,
+                Value::ValueArray(ValueArray::Float(vec![0.25, 0.5])),
+            )],
+        )
+        .unwrap();
+        let pod = Pod::from_bytes(&bytes).unwrap();
+        assert_eq!(parse_route(pod), Some(route));
+
+        let mut props = NodeProps::default();
+        let mut save = false;
+        for property in deserialize(pod).unwrap().properties {
+            match (property.key, property.value) {
+                (pw::spa::sys::SPA_PARAM_ROUTE_props, Value::Object(object)) => {
+                    read_props(object, &mut props)
+                }
+                (pw::spa::sys::SPA_PARAM_ROUTE_save, Value::Bool(value)) => save = value,
+                _ => {}
+            }
+        }
+        assert_eq!(props.channel_volumes, vec![0.25, 0.5]);
+        assert!(save);
+    }
+
+    #[test]
+    fn needs_an_index_and_a_device_for_a_route() {
+        let bytes = serialize_props(vec![Property::new(
+            pw::spa::sys::SPA_PARAM_ROUTE_index,
+            Value::Int(3),
+        )])
+        .unwrap();
+        assert_eq!(parse_route(Pod::from_bytes(&bytes).unwrap()), None);
+    }
+
+    #[test]
+    fn has_no_volumes_before_the_props_arrive() {
+        assert_eq!(NodeProps::default().volumes(), None);
+        let props = parsed(vec![Property::new(
+            pw::spa::sys::SPA_PROP_mute,
+            Value::Bool(false),
+        )]);
+        assert_eq!(props.volumes(), None);
+        let too_many = NodeProps {
+            channel_volumes: vec![1.0; CHANNELS_MAX + 1],
+            mute: false,
+        };
+        assert_eq!(too_many.volumes(), None);
+    }
+}
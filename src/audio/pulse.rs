@@ -0,0 +1,369 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use libpulse_binding::channelmap::Map;
+use libpulse_binding::context::subscribe::{subscription_masks, Facility};
+use libpulse_binding::context::State;
+use libpulse_binding::def::INVALID_INDEX;
+use libpulse_binding::mainloop::standard::IterateResult;
+use libpulse_binding::volume::ChannelVolumes;
+use pulsectl::controllers::types::DeviceInfo;
+use pulsectl::controllers::{DeviceControl, SinkController};
+use pulsectl::Handler;
+
+use super::cache::{SinkCache, SinkEvent, SinkState};
+use super::{AudioBackend, AudioError, EqCurve, Sink};
+
+/// Equalizer sinks are named after their master, e.g. `nommo_eq.alsa_output.usb-Razer`.
+const EQ_SINK_PREFIX: &str = "nommo_eq.";
+/// Steve Harris' multiband EQ from swh-plugins.
+const EQ_PLUGIN: &str = "mbeq_1197";
+const EQ_LABEL: &str = "mbeq";
+/// Centre frequencies of the `mbeq` bands, in Hz.
+const EQ_BANDS: [f64; 15] = [
+    50.0, 100.0, 156.0, 220.0, 311.0, 440.0, 622.0, 880.0, 1250.0, 1750.0, 2500.0, 3500.0, 5000.0,
+    10000.0, 20000.0,
+];
+
+/// Talks to PulseAudio (or pipewire-pulse) through `pulsectl`.
+///
+/// Subscribes to server and sink events, so the default sink and its volume and mute are
+/// only fetched again after something changed them.
+pub struct PulseBackend {
+    controller: SinkController,
+    /// Filled by the subscription callback whenever the main loop runs.
+    events: Rc<RefCell<Vec<SinkEvent>>>,
+    cache: SinkCache,
+}
+
+impl PulseBackend {
+    pub fn connect() -> Result<Self, String> {
+        let mut handler = Handler::connect("nommo_vol_driver")
+            .map_err(|e| format!("Cannot connect to PulseAudio: {:?}", e))?;
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let op = {
+            let events = events.clone();
+            let mut context = handler.context.borrow_mut();
+            context.set_subscribe_callback(Some(Box::new(move |facility, _, index| {
+                let event = match facility {
+                    Some(Facility::Server) => SinkEvent::Server,
+                    Some(Facility::Sink) => SinkEvent::Sink(index),
+                    _ => return,
+                };
+                events.borrow_mut().push(event);
+            })));
+            context.subscribe(
+                subscription_masks::SERVER | subscription_masks::SINK,
+                |_| {},
+            )
+        };
+        handler
+            .wait_for_operation(op)
+            .map_err(|e| format!("Cannot subscribe to PulseAudio events: {:?}", e))?;
+
+        Ok(PulseBackend {
+            controller: SinkController { handler },
+            events,
+            cache: SinkCache::default(),
+        })
+    }
+
+    /// Dispatches the events that arrived since the main loop last ran, without blocking.
+    fn receive_events(&mut self) -> Result<(), String> {
+        loop {
+            match self.controller.handler.mainloop.borrow_mut().iterate(false) {
+                IterateResult::Success(0) => break,
+                IterateResult::Success(_) => {}
+                IterateResult::Quit(_) | IterateResult::Err(_) => {
+                    return Err(String::from("Lost connection to PulseAudio"));
+                }
+            }
+        }
+        for event in self.events.borrow_mut().drain(..) {
+            self.cache.handle(event);
+        }
+        Ok(())
+    }
+
+    fn default_state(&mut self) -> Result<&SinkState, String> {
+        self.receive_events()?;
+        let controller = &mut self.controller;
+        self.cache.default_sink(|| fetch_default(controller))
+    }
+
+    /// A failed request only means a lost connection if the context is no longer ready;
+    /// otherwise PulseAudio turned it down, e.g. for a sink that does not exist.
+    fn refused(&self, message: String, refusal: fn(String) -> AudioError) -> AudioError {
+        match self.controller.handler.context.borrow().get_state() {
+            State::Ready => refusal(message),
+            _ => AudioError::Disconnected(message),
+        }
+    }
+
+    fn device(&mut self, sink: &Sink) -> Result<DeviceInfo, AudioError> {
+        self.controller
+            .get_device_by_index(sink.index)
+            .map_err(|e| {
+                let message = format!("Cannot get PulseAudio sink {}: {:?}", sink.index, e);
+                self.refused(message, AudioError::NotFound)
+            })
+    }
+
+    fn default_sink_name(&mut self) -> Result<String, String> {
+        self.controller
+            .get_server_info()
+            .map_err(|e| format!("Cannot get PulseAudio server info: {:?}", e))?
+            .default_sink_name
+            .ok_or_else(|| String::from("PulseAudio has no default sink"))
+    }
+
+    fn unload_module(&mut self, index: u32) -> Result<(), String> {
+        let op = self
+            .controller
+            .handler
+            .introspect
+            .unload_module(index, |_| {});
+        self.controller
+            .handler
+            .wait_for_operation(op)
+            .map_err(|e| format!("Error unloading module {}: {:?}", index, e))
+    }
+
+    fn load_module(&mut self, name: &str, argument: &str) -> Result<u32, AudioError> {
+        let index = Rc::new(Cell::new(INVALID_INDEX));
+        let op = {
+            let index = index.clone();
+            self.controller
+                .handler
+                .introspect
+                .load_module(name, argument, move |loaded| index.set(loaded))
+        };
+        self.controller
+            .handler
+            .wait_for_operation(op)
+            .map_err(|e| format!("Error loading {}: {:?}", name, e))?;
+        match index.get() {
+            INVALID_INDEX => {
+                let message = format!("Cannot load {} {}", name, argument);
+                Err(self.refused(message, AudioError::Unsupported))
+            }
+            index => Ok(index),
+        }
+    }
+}
+
+/// The default sink with its state; with our equalizer in front of it, the sink the
+/// equalizer feeds into.
+fn fetch_default(controller: &mut SinkController) -> Result<SinkState, String> {
+    let mut device = controller
+        .get_default_device()
+        .map_err(|e| format!("Cannot get PulseAudio default sink: {:?}", e))?;
+    let name = device.name.clone().unwrap_or_default();
+    if name.starts_with(EQ_SINK_PREFIX) {
+        if let Some(master) = device.proplist.get_str("device.master_device") {
+            device = controller
+                .get_device_by_name(&master)
+                .map_err(|e| format!("Cannot get PulseAudio sink {}: {:?}", master, e))?;
+        }
+    }
+    Ok(SinkState {
+        sink: Sink {
+            index: device.index,
+            name: device.name.unwrap_or_default(),
+        },
+        volumes: device.volume,
+        mute: device.mute,
+    })
+}
+
+/// `control` argument for `mbeq`: bass gain below ~150 Hz and treble gain above ~5 kHz,
+/// each fading out over about an octave and a half on a log-frequency scale.
+fn eq_controls(curve: &EqCurve) -> String {
+    let weight = |from: f64, to: f64, freq: f64| {
+        ((freq.log2() - from.log2()) / (to.log2() - from.log2())).clamp(0.0, 1.0)
+    };
+    EQ_BANDS
+        .iter()
+        .map(|freq| {
+            let gain = curve.bass * weight(440.0, 156.0, *freq)
+                + curve.treble * weight(1750.0, 5000.0, *freq);
+            // rounded first, so small cuts print as 0.0; adding 0.0 turns the -0.0 they round
+            // to into 0.0
+            let gain = (gain * 10.0).round() / 10.0 + 0.0;
+            format!("{:.1}", gain)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl AudioBackend for PulseBackend {
+    fn default_sink(&mut self) -> Result<Sink, AudioError> {
+        Ok(self.default_state()?.sink.clone())
+    }
+
+    fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError> {
+        let device = self.controller.get_device_by_name(name).map_err(|e| {
+            let message = format!("Cannot get PulseAudio sink {}: {:?}", name, e);
+            self.refused(message, AudioError::NotFound)
+        })?;
+        Ok(Sink {
+            index: device.index,
+            name: device.name.unwrap_or_default(),
+        })
+    }
+
+    fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError> {
+        self.receive_events()?;
+        match self.cache.get(sink) {
+            Some(state) => Ok(state.volumes),
+            None => self.device(sink).map(|device| device.volume),
+        }
+    }
+
+    fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError> {
+        let op = self
+            .controller
+            .handler
+            .introspect
+            .set_sink_volume_by_index(sink.index, volumes, None);
+        self.controller
+            .handler
+            .wait_for_operation(op)
+            .map_err(|e| format!("Error setting volume: {:?}", e))?;
+        self.cache.set_volume(sink, volumes);
+        Ok(())
+    }
+
+    fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError> {
+        self.receive_events()?;
+        match self.cache.get(sink) {
+            Some(state) => Ok(state.mute),
+            None => self.device(sink).map(|device| device.mute),
+        }
+    }
+
+    fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError> {
+        let op = self
+            .controller
+            .handler
+            .introspect
+            .set_sink_mute_by_index(sink.index, mute, None);
+        self.controller
+            .handler
+            .wait_for_operation(op)
+            .map_err(|e| format!("Error setting mute: {:?}", e))?;
+        self.cache.set_mute(sink, mute);
+        Ok(())
+    }
+
+    /// Loads `module-ladspa-sink` with the curve in front of `sink`, replacing our previous
+    /// equalizer on it, and makes it the default sink if `sink` was.
+    fn set_equalizer(&mut self, sink: &Sink, curve: &EqCurve) -> Result<(), AudioError> {
+        let eq_name = format!("{}{}", EQ_SINK_PREFIX, sink.name);
+        let default_name = self.default_sink_name()?;
+        let was_default = default_name == sink.name || default_name == eq_name;
+
+        if let Ok(previous) = self.controller.get_device_by_name(&eq_name) {
+            if let Some(module) = previous.owner_module {
+                self.unload_module(module)?;
+            }
+        }
+        let argument = format!(
+            "sink_name={} sink_master={} plugin={} label={} control={}",
+            eq_name,
+            sink.name,
+            EQ_PLUGIN,
+            EQ_LABEL,
+            eq_controls(curve)
+        );
+        self.load_module("module-ladspa-sink", &argument)?;
+
+        if was_default {
+            self.controller
+                .set_default_device(&eq_name)
+                .map_err(|e| format!("Cannot make {} the default sink: {:?}", eq_name, e))?;
+        }
+        Ok(())
+    }
+
+    fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError> {
+        self.controller
+            .set_default_device(&sink.name)
+            .map(|_| ())
+            .map_err(|e| {
+                let message = format!("Cannot make {} the default sink: {:?}", sink.name, e);
+                self.refused(message, AudioError::NotFound)
+            })
+    }
+
+    fn channel_map(&mut self, sink: &Sink) -> Result<Map, AudioError> {
+        self.device(sink).map(|device| device.channel_map)
+    }
+
+    fn changes(&mut self) -> Result<Option<SinkState>, AudioError> {
+        self.receive_events()?;
+        let controller = &mut self.controller;
+        Ok(self.cache.changes(|| fetch_default(controller))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controls(bass: f64, treble: f64) -> Vec<String> {
+        eq_controls(&EqCurve { bass, treble })
+            .split(',')
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn bass_boosts_the_low_bands_fading_out_by_440_hz() {
+        assert_eq!(
+            controls(6.0, 0.0),
+            [
+                "6.0", "6.0", "6.0", "4.0", "2.0", "0.0", "0.0", "0.0", "0.0", "0.0", "0.0", "0.0",
+                "0.0", "0.0", "0.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn treble_boosts_the_high_bands_fading_in_from_1750_hz() {
+        assert_eq!(
+            controls(0.0, -3.0),
+            [
+                "0.0", "0.0", "0.0", "0.0", "0.0", "0.0", "0.0", "0.0", "0.0", "0.0", "-1.0",
+                "-2.0", "-3.0", "-3.0", "-3.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn bass_and_treble_leave_the_middle_bands_alone() {
+        let gains = controls(24.0, 24.0);
+        assert_eq!(gains.len(), EQ_BANDS.len());
+        for (freq, gain) in EQ_BANDS.iter().zip(&gains) {
+            if (440.0..=1750.0).contains(freq) {
+                assert_eq!(gain, "0.0", "{} Hz", freq);
+            } else {
+                assert_ne!(gain, "0.0", "{} Hz", freq);
+            }
+        }
+        assert_eq!(controls(0.0, 0.0), vec!["0.0"; EQ_BANDS.len()]);
+    }
+
+    #[test]
+    fn small_cuts_print_as_zero() {
+        assert_eq!(controls(-0.01, 0.0), vec!["0.0"; EQ_BANDS.len()]);
+        assert_eq!(controls(0.0, -0.04), vec!["0.0"; EQ_BANDS.len()]);
+        for bass in [-24.0, -3.3, -0.5, -0.01] {
+            for treble in [-24.0, -0.7, -0.01, 0.0] {
+                let gains = controls(bass, treble);
+                assert!(!gains.iter().any(|gain| gain == "-0.0"), "{:?}", gains);
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use libpulse_binding::channelmap::Map;
+use libpulse_binding::volume::ChannelVolumes;
+
+use super::{connect, AudioBackend, AudioError, EqCurve, MemoryMixer, Sink, SinkState};
+use crate::config::BackendConfig;
+
+type Connect = Box<dyn FnMut() -> Result<Box<dyn AudioBackend>, String>>;
+
+/// Wraps another backend and reconnects to it on the call after one lost the connection, so
+/// the driver survives a restart of the sound server.
+///
+/// Other failures, such as a sink that does not exist, keep the connection.
+pub struct Reconnecting {
+    connect: Connect,
+    backend: Option<Box<dyn AudioBackend>>,
+}
+
+impl Reconnecting {
+    /// Connects right away, so a sound server that is not running is reported at startup. The
+    /// `memory` backend connects to `mixer`.
+    pub fn open(config: &BackendConfig, mixer: &MemoryMixer) -> Result<Self, String> {
+        let (config, mixer) = (config.clone(), mixer.clone());
+        Self::new(move || connect(&config, &mixer))
+    }
+
+    /// Like `open`, making every connection with `connect`.
+    pub fn new(
+        connect: impl FnMut() -> Result<Box<dyn AudioBackend>, String> + 'static,
+    ) -> Result<Self, String> {
+        let mut connect: Connect = Box::new(connect);
+        Ok(Reconnecting {
+            backend: Some(connect()?),
+            connect,
+        })
+    }
+
+    fn call<T>(
+        &mut self,
+        f: impl FnOnce(&mut dyn AudioBackend) -> Result<T, AudioError>,
+    ) -> Result<T, AudioError> {
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => self.backend.insert((self.connect)()?),
+        };
+        let result = f(backend.as_mut());
+        if matches!(&result, Err(error) if error.is_disconnected()) {
+            self.backend = None;
+        }
+        result
+    }
+}
+
+impl AudioBackend for Reconnecting {
+    fn default_sink(&mut self) -> Result<Sink, AudioError> {
+        self.call(|backend| backend.default_sink())
+    }
+
+    fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError> {
+        self.call(|backend| backend.find_sink(name))
+    }
+
+    fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError> {
+        self.call(|backend| backend.volume(sink))
+    }
+
+    fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError> {
+        self.call(|backend| backend.set_volume(sink, volumes))
+    }
+
+    fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError> {
+        self.call(|backend| backend.mute(sink))
+    }
+
+    fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError> {
+        self.call(|backend| backend.set_mute(sink, mute))
+    }
+
+    fn set_equalizer(&mut self, sink: &Sink, curve: &EqCurve) -> Result<(), AudioError> {
+        self.call(|backend| backend.set_equalizer(sink, curve))
+    }
+
+    fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError> {
+        self.call(|backend| backend.set_default_sink(sink))
+    }
+
+    fn channel_map(&mut self, sink: &Sink) -> Result<Map, AudioError> {
+        self.call(|backend| backend.channel_map(sink))
+    }
+
+    fn changes(&mut self) -> Result<Option<SinkState>, AudioError> {
+        self.call(|backend| backend.changes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpulse_binding::volume::Volume;
+
+    use super::*;
+    use crate::audio::RecordingMixer;
+
+    #[test]
+    fn reconnects_on_the_call_after_a_failure() {
+        let mixer = RecordingMixer::new(Volume(0), 2);
+        let (mut backend, connects) = mixer.reconnecting();
+        mixer.fail_calls(1);
+        assert!(backend.default_sink().is_err());
+        assert_eq!(connects.get(), 1);
+        assert!(backend.default_sink().is_ok());
+        assert_eq!(connects.get(), 2);
+        assert!(backend.default_sink().is_ok());
+        assert_eq!(connects.get(), 2);
+    }
+
+    #[test]
+    fn keeps_the_connection_when_a_sink_is_missing() {
+        let mixer = RecordingMixer::new(Volume(0), 2);
+        let (mut backend, connects) = mixer.reconnecting();
+        assert_eq!(
+            backend.find_sink("hdmi"),
+            Err(AudioError::NotFound(String::from("No such sink: hdmi")))
+        );
+        assert!(backend.default_sink().is_ok());
+        assert_eq!(connects.get(), 1);
+    }
+
+    #[test]
+    fn reports_a_failed_connection() {
+        let mixer = RecordingMixer::new(Volume(0), 2);
+        let mut backend = {
+            let mixer = mixer.clone();
+            let mut connected = false;
+            Reconnecting::new(move || {
+                if connected {
+                    return Err(String::from("Server gone"));
+                }
+                connected = true;
+                Ok(Box::new(mixer.connect()))
+            })
+            .unwrap()
+        };
+        mixer.fail_calls(1);
+        assert!(backend.default_sink().is_err());
+        assert_eq!(
+            backend.default_sink(),
+            Err(AudioError::Disconnected(String::from("Server gone")))
+        );
+        assert!(Reconnecting::new(|| Err(String::from("Server gone"))).is_err());
+    }
+}
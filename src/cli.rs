@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+
+use clap::builder::PossibleValuesParser;
+use clap::Parser;
+
+/// Audio backends compiled into this binary.
+pub const BACKENDS: &[&str] = &[
+    "pulse",
+    #[cfg(feature = "pipewire")]
+    "pipewire",
+    #[cfg(feature = "alsa")]
+    "alsa",
+    "memory",
+];
+
+/// Volume knob driver for Razer Nommo speakers.
+#[derive(Debug, Clone, Parser)]
+#[command(version)]
+pub struct Args {
+    /// Configuration file [default: $XDG_CONFIG_HOME/nommo_vol_driver/config.toml]
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// USB vendor ID of the speaker, in hex [default: any known model]
+    #[arg(long, value_name = "HEX", value_parser = parse_hex_id)]
+    pub vid: Option<u16>,
+
+    /// USB product ID of the speaker, in hex [default: any known model]
+    #[arg(long, value_name = "HEX", value_parser = parse_hex_id)]
+    pub pid: Option<u16>,
+
+    /// Volume change per knob detent, in percent [default: 5]
+    #[arg(long, value_name = "PERCENT", value_parser = parse_step)]
+    pub step: Option<f64>,
+
+    /// Highest volume the knob can raise to, in percent [default: 100]
+    #[arg(long, value_name = "PERCENT", value_parser = parse_max_volume)]
+    pub max_volume: Option<f64>,
+
+    /// Wait for the speaker to be plugged in instead of exiting when it is missing
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Sound system to control [default: pulse]
+    #[arg(long, value_parser = PossibleValuesParser::new(BACKENDS))]
+    pub backend: Option<String>,
+
+    /// Sink to control instead of the default one
+    #[arg(long, value_name = "NAME")]
+    pub sink: Option<String>,
+
+    /// ALSA card to open with the alsa backend [default: default]
+    #[arg(long, value_name = "CARD")]
+    pub alsa_card: Option<String>,
+
+    /// ALSA simple mixer control to adjust with the alsa backend [default: Master]
+    #[arg(long, value_name = "CONTROL")]
+    pub alsa_control: Option<String>,
+
+    /// Read reports from a file of hex-encoded lines instead of the device
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+
+    /// Print every decoded message and volume change
+    #[arg(short, long)]
+    pub verbose: bool,
+}
+
+fn parse_hex_id(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16)
+        .map_err(|_| format!("`{}` is not a 16-bit hexadecimal ID", value))
+}
+
+fn parse_percent(value: &str, min: f64, max: f64) -> Result<f64, String> {
+    let percent: f64 = value
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", value))?;
+    if percent > min && percent <= max {
+        Ok(percent)
+    } else {
+        Err(format!("must be above {} and at most {}", min, max))
+    }
+}
+
+fn parse_step(value: &str) -> Result<f64, String> {
+    parse_percent(value, 0.0, 100.0)
+}
+
+fn parse_max_volume(value: &str) -> Result<f64, String> {
+    parse_percent(value, 0.0, 150.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+
+    use super::*;
+
+    fn parse(flags: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(["nommo_vol_driver"].iter().chain(flags))
+    }
+
+    /// The message `flags` are rejected with.
+    fn rejection(flags: &[&str]) -> String {
+        let error = parse(flags).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ValueValidation, "{}", error);
+        error.to_string()
+    }
+
+    #[test]
+    fn reads_hex_ids_with_or_without_a_prefix() {
+        for (flag, id) in [("0x1532", 0x1532), ("0X0517", 0x0517), ("ffff", 0xffff)] {
+            let args = parse(&["--vid", flag, "--pid", flag]).unwrap();
+            assert_eq!((args.vid, args.pid), (Some(id), Some(id)), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn rejects_ids_that_are_not_16_bit_hex() {
+        for flag in ["0x", "10000", "0xg1", ""] {
+            assert!(
+                rejection(&["--vid", flag])
+                    .contains(&format!("`{}` is not a 16-bit hexadecimal ID", flag)),
+                "{}",
+                flag
+            );
+        }
+        assert!(rejection(&["--pid=-1"]).contains("`-1` is not a 16-bit hexadecimal ID"));
+    }
+
+    #[test]
+    fn reads_steps_and_volume_limits_in_their_range() {
+        let args = parse(&["--step", "0.5", "--max-volume", "150"]).unwrap();
+        assert_eq!(args.step, Some(0.5));
+        assert_eq!(args.max_volume, Some(150.0));
+        assert_eq!(parse(&["--step", "100"]).unwrap().step, Some(100.0));
+    }
+
+    #[test]
+    fn rejects_steps_out_of_range_or_not_numbers() {
+        assert!(rejection(&["--step", "0"]).contains("must be above 0 and at most 100"));
+        assert!(rejection(&["--step", "100.1"]).contains("must be above 0 and at most 100"));
+        assert!(rejection(&["--step", "abc"]).contains("`abc` is not a number"));
+    }
+
+    #[test]
+    fn rejects_volume_limits_out_of_range() {
+        assert!(rejection(&["--max-volume", "151"]).contains("must be above 0 and at most 150"));
+        assert!(rejection(&["--max-volume", "0"]).contains("must be above 0 and at most 150"));
+    }
+
+    #[test]
+    fn takes_only_the_backends_compiled_in() {
+        for backend in BACKENDS {
+            assert_eq!(
+                parse(&["--backend", backend]).unwrap().backend.as_deref(),
+                Some(*backend)
+            );
+        }
+        let error = parse(&["--backend", "jack"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidValue);
+        assert!(error.to_string().contains("'jack'"), "{}", error);
+    }
+}
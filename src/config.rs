@@ -0,0 +1,980 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use toml_edit::ImDocument;
+
+use crate::audio::{self, EqCurve};
+use crate::cli::{Args, BACKENDS};
+use crate::curve::Curve;
+use crate::device::DeviceId;
+use crate::media::MediaCommand;
+use crate::rules::{self, Rule};
+use crate::NommoMsg;
+
+const CONFIG_DIR: &str = "nommo_vol_driver";
+const CONFIG_FILE: &str = "config.toml";
+
+/// One step on the way to a setting in the config file: a key, or an index into an array.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Name(&'static str),
+    Index(usize),
+}
+
+/// A setting that failed validation, with the keys leading to it.
+#[derive(Debug, Clone, PartialEq)]
+struct Invalid {
+    keys: Vec<Key>,
+    message: String,
+}
+
+impl Invalid {
+    /// An error about the setting at `names`, e.g. `["volume", "step"]`.
+    fn at(names: &[&'static str], message: String) -> Self {
+        Invalid {
+            keys: names.iter().map(|name| Key::Name(name)).collect(),
+            message,
+        }
+    }
+
+    /// Moves the error into the `index`th table of the array at `names`, e.g. the second
+    /// `[[binding]]`.
+    fn within(mut self, names: &[&'static str], index: usize) -> Self {
+        let mut keys: Vec<Key> = names.iter().map(|name| Key::Name(name)).collect();
+        keys.push(Key::Index(index));
+        keys.append(&mut self.keys);
+        self.keys = keys;
+        self
+    }
+
+    /// The message, preceded by the line of the setting in `contents` if it is set there, or
+    /// else of the nearest table holding it.
+    fn locate(self, contents: &str) -> String {
+        match line_of(contents, &self.keys) {
+            Some(line) => format!("line {}: {}", line, self.message),
+            None => self.message,
+        }
+    }
+}
+
+/// The line, counting from 1, where the deepest of `keys` found in `contents` is set.
+fn line_of(contents: &str, keys: &[Key]) -> Option<usize> {
+    let document = ImDocument::parse(contents).ok()?;
+    let mut item = document.as_item();
+    let mut span = None;
+    for key in keys {
+        let found = match *key {
+            Key::Name(name) => item
+                .as_table_like()
+                .and_then(|table| table.get_key_value(name))
+                .map(|(key, value)| (value, key.span())),
+            Key::Index(index) => item.get(index).map(|value| (value, value.span())),
+        };
+        match found {
+            Some((value, value_span)) => {
+                item = value;
+                span = value_span.or(span);
+            }
+            None => break,
+        }
+    }
+    span.map(|span| contents[..span.start].matches('\n').count() + 1)
+}
+
+/// What a decoded device message should do.
+///
+/// Written as a string for actions without arguments, e.g. `"toggle-mute"`, and as a table
+/// otherwise, e.g. `{ command = "playerctl play-pause" }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    VolumeUp,
+    VolumeDown,
+    ToggleMute,
+    /// Apply the equalizer preset for the EQ value; only meaningful for EQ values.
+    Equalizer,
+    /// Run a shell command, with the EQ value or button number in `NOMMO_VALUE`.
+    Command(String),
+    /// Make the named sink the default one.
+    SwitchSink(String),
+    /// Control the media player picked by `[media]`.
+    Media(MediaCommand),
+    /// Switch between changing the volume and shifting the left/right balance.
+    ToggleBalance,
+    Ignore,
+}
+
+/// Devices to drive; without `vid` and `pid` every model with a profile is picked up.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    /// Keep waiting for the device instead of exiting when it is not connected at startup.
+    pub wait: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub name: String,
+    pub alsa_card: String,
+    pub alsa_control: String,
+    /// Channel count of the `memory` backend's sink.
+    pub memory_channels: u8,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            name: String::from("pulse"),
+            alsa_card: String::from("default"),
+            alsa_control: String::from("Master"),
+            memory_channels: 2,
+        }
+    }
+}
+
+/// Volume settings; `step`, `max_volume` and `balance` are in percent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeConfig {
+    pub step: f64,
+    pub max_volume: f64,
+    /// Balance set on every volume change, from -100 (left only) to 100 (right only); at 0
+    /// the sink keeps whatever balance it has.
+    pub balance: f64,
+    pub curve: Curve,
+    /// Turns arriving within this long of the first one are applied as one change; 0 applies
+    /// every turn on its own.
+    pub coalesce_ms: u64,
+    pub sink: Option<String>,
+}
+
+impl Default for VolumeConfig {
+    fn default() -> Self {
+        VolumeConfig {
+            step: 5.0,
+            max_volume: 100.0,
+            balance: 0.0,
+            curve: Curve::default(),
+            coalesce_ms: 30,
+            sink: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MuteConfig {
+    /// Mute once the knob is turned down while already at 0%.
+    pub mute_at_zero: bool,
+    /// Unmute whenever the volume is raised.
+    pub unmute_on_raise: bool,
+}
+
+impl Default for MuteConfig {
+    fn default() -> Self {
+        MuteConfig {
+            mute_at_zero: true,
+            unmute_on_raise: true,
+        }
+    }
+}
+
+/// Binds the EQ values from `min` to `max`, inclusive, to an action.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EqBinding {
+    pub min: u8,
+    pub max: u8,
+    pub action: Action,
+}
+
+/// Binds a button, numbered by the decoding rules, to an action.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ButtonBinding {
+    pub button: u8,
+    pub action: Action,
+}
+
+/// Action bound to each `NommoMsg` variant.
+///
+/// EQ values use the first `eq` range containing them, falling back to `eq_value`; buttons
+/// without a binding are ignored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mappings {
+    pub vol_up: Action,
+    pub vol_down: Action,
+    pub eq_value: Action,
+    #[serde(rename = "eq", skip_serializing_if = "Vec::is_empty")]
+    pub eq_ranges: Vec<EqBinding>,
+    #[serde(rename = "button", skip_serializing_if = "Vec::is_empty")]
+    pub buttons: Vec<ButtonBinding>,
+}
+
+impl Default for Mappings {
+    fn default() -> Self {
+        Mappings {
+            vol_up: Action::VolumeUp,
+            vol_down: Action::VolumeDown,
+            eq_value: Action::Ignore,
+            eq_ranges: vec![],
+            buttons: vec![],
+        }
+    }
+}
+
+impl Mappings {
+    pub fn action(&self, msg: &NommoMsg) -> &Action {
+        match msg {
+            NommoMsg::VolUp => &self.vol_up,
+            NommoMsg::VolDown => &self.vol_down,
+            NommoMsg::EqValue(value) => self
+                .eq_ranges
+                .iter()
+                .find(|range| (range.min..=range.max).contains(value))
+                .map_or(&self.eq_value, |range| &range.action),
+            NommoMsg::Button(button) => self
+                .buttons
+                .iter()
+                .find(|binding| binding.button == *button)
+                .map_or(&Action::Ignore, |binding| &binding.action),
+            NommoMsg::Noop => &Action::Ignore,
+        }
+    }
+
+    fn validate(&self) -> Result<(), Invalid> {
+        for (index, range) in self.eq_ranges.iter().enumerate() {
+            if range.min > range.max {
+                return Err(Invalid::at(
+                    &[],
+                    format!("mappings.eq: range {}..={} is empty", range.min, range.max),
+                )
+                .within(&["mappings", "eq"], index));
+            }
+        }
+        let message = || String::from("mappings: only EQ values can be mapped to `equalizer`");
+        for (key, action) in [("vol_up", &self.vol_up), ("vol_down", &self.vol_down)] {
+            if *action == Action::Equalizer {
+                return Err(Invalid::at(&["mappings", key], message()));
+            }
+        }
+        if let Some(index) = self
+            .buttons
+            .iter()
+            .position(|binding| binding.action == Action::Equalizer)
+        {
+            return Err(Invalid::at(&["action"], message()).within(&["mappings", "button"], index));
+        }
+        Ok(())
+    }
+
+    fn uses_equalizer(&self) -> bool {
+        self.eq_value == Action::Equalizer
+            || self
+                .eq_ranges
+                .iter()
+                .any(|range| range.action == Action::Equalizer)
+    }
+}
+
+/// Equalizer curve for a set of EQ knob values; gains are in dB.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EqPreset {
+    pub values: Vec<u8>,
+    #[serde(default)]
+    pub bass: f64,
+    #[serde(default)]
+    pub treble: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EqualizerConfig {
+    #[serde(rename = "preset", skip_serializing_if = "Vec::is_empty")]
+    pub presets: Vec<EqPreset>,
+}
+
+impl EqualizerConfig {
+    /// The curve of the preset listing `value`, if any.
+    pub fn curve(&self, value: u8) -> Option<EqCurve> {
+        self.presets
+            .iter()
+            .find(|preset| preset.values.contains(&value))
+            .map(|preset| EqCurve {
+                bass: preset.bass,
+                treble: preset.treble,
+            })
+    }
+
+    fn validate(&self) -> Result<(), Invalid> {
+        let mut seen = HashSet::new();
+        for (index, preset) in self.presets.iter().enumerate() {
+            let invalid =
+                |key, message| Invalid::at(&[key], message).within(&["equalizer", "preset"], index);
+            if preset.values.is_empty() {
+                return Err(invalid(
+                    "values",
+                    String::from("equalizer.preset: values must not be empty"),
+                ));
+            }
+            if let Some(value) = preset.values.iter().find(|value| !seen.insert(**value)) {
+                return Err(invalid(
+                    "values",
+                    format!(
+                        "equalizer.preset: EQ value {} is in more than one preset",
+                        value
+                    ),
+                ));
+            }
+            for (band, gain) in [("bass", preset.bass), ("treble", preset.treble)] {
+                if !(-24.0..=24.0).contains(&gain) {
+                    return Err(invalid(
+                        band,
+                        format!(
+                            "equalizer.preset.{}: {} must be between -24 and 24 dB",
+                            band, gain
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Media player that `media` actions control.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    /// MPRIS name of the player, e.g. `spotify`; without it the playing one is picked.
+    pub player: Option<String>,
+}
+
+/// Step used when the knob turns within `within_ms` of its previous turn, in percent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccelerationLevel {
+    pub within_ms: u64,
+    pub step: f64,
+}
+
+/// Volume steps that grow with the speed of the knob, replacing `volume.step` when enabled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccelerationConfig {
+    pub enabled: bool,
+    /// Step for slow turns that no level covers, in percent.
+    pub fine_step: f64,
+    #[serde(rename = "level")]
+    pub levels: Vec<AccelerationLevel>,
+}
+
+impl Default for AccelerationConfig {
+    fn default() -> Self {
+        AccelerationConfig {
+            enabled: false,
+            fine_step: 1.0,
+            levels: vec![
+                AccelerationLevel {
+                    within_ms: 250,
+                    step: 5.0,
+                },
+                AccelerationLevel {
+                    within_ms: 80,
+                    step: 10.0,
+                },
+            ],
+        }
+    }
+}
+
+impl AccelerationConfig {
+    fn validate(&self) -> Result<(), Invalid> {
+        let message = |step| {
+            format!(
+                "acceleration: step {} must be above 0 and at most 100",
+                step
+            )
+        };
+        let in_range = |step: f64| step > 0.0 && step <= 100.0;
+        if !in_range(self.fine_step) {
+            return Err(Invalid::at(
+                &["acceleration", "fine_step"],
+                message(self.fine_step),
+            ));
+        }
+        for (index, level) in self.levels.iter().enumerate() {
+            if !in_range(level.step) {
+                return Err(Invalid::at(&["step"], message(level.step))
+                    .within(&["acceleration", "level"], index));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The session D-Bus service publishing the driver's status.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbusConfig {
+    pub enabled: bool,
+}
+
+impl Default for DbusConfig {
+    fn default() -> Self {
+        DbusConfig { enabled: true }
+    }
+}
+
+/// Desktop notifications showing each volume change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    pub enabled: bool,
+    /// Shortest time between two notifications; changes in between are merged.
+    pub interval_ms: u64,
+    /// How long a notification stays up, or -1 for the notification server's default.
+    pub timeout_ms: i32,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig {
+            enabled: false,
+            interval_ms: 100,
+            timeout_ms: -1,
+        }
+    }
+}
+
+/// Gives one device, picked by serial number or HID path, its own sink or volume curve.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    pub serial: Option<String>,
+    pub path: Option<String>,
+    pub sink: Option<String>,
+    pub curve: Option<Curve>,
+}
+
+impl Binding {
+    fn matches(&self, device: &DeviceId) -> bool {
+        match (&self.serial, &self.path) {
+            (Some(serial), _) => device.serial.as_ref() == Some(serial),
+            (None, Some(path)) => &device.path == path,
+            (None, None) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub device: DeviceConfig,
+    pub backend: BackendConfig,
+    pub volume: VolumeConfig,
+    pub acceleration: AccelerationConfig,
+    pub mute: MuteConfig,
+    pub mappings: Mappings,
+    pub equalizer: EqualizerConfig,
+    pub media: MediaConfig,
+    pub dbus: DbusConfig,
+    pub notifications: NotificationsConfig,
+    #[serde(rename = "binding", skip_serializing_if = "Vec::is_empty")]
+    pub bindings: Vec<Binding>,
+    /// Extra decoding rules, tried before the built-in ones of the device's profile.
+    #[serde(rename = "rule", skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/nommo_vol_driver/config.toml`, falling back to `~/.config`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_home.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parses a config file; errors name the setting at fault and, where the file sets it,
+    /// its line.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        config.check().map_err(|invalid| invalid.locate(contents))?;
+        Ok(config)
+    }
+
+    /// The file given on the command line, or the default one.
+    pub fn path(args: &Args) -> Option<PathBuf> {
+        args.config.clone().or_else(Self::default_path)
+    }
+
+    /// Loads the file given on the command line, or the default one if it exists,
+    /// and applies command-line overrides on top.
+    pub fn load(args: &Args) -> Result<Self, String> {
+        let mut config = match Self::path(args) {
+            Some(path) if args.config.is_some() || path.exists() => Self::from_file(&path)?,
+            _ => Config::default(),
+        };
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if args.vid.is_some() {
+            self.device.vid = args.vid;
+        }
+        if args.pid.is_some() {
+            self.device.pid = args.pid;
+        }
+        if args.wait {
+            self.device.wait = true;
+        }
+        if let Some(backend) = &args.backend {
+            self.backend.name = backend.clone();
+        }
+        if let Some(card) = &args.alsa_card {
+            self.backend.alsa_card = card.clone();
+        }
+        if let Some(control) = &args.alsa_control {
+            self.backend.alsa_control = control.clone();
+        }
+        if let Some(step) = args.step {
+            self.volume.step = step;
+        }
+        if let Some(max_volume) = args.max_volume {
+            self.volume.max_volume = max_volume;
+        }
+        if let Some(sink) = &args.sink {
+            self.volume.sink = Some(sink.clone());
+        }
+    }
+
+    fn binding_for(&self, device: Option<&DeviceId>) -> Option<&Binding> {
+        device.and_then(|device| self.bindings.iter().find(|binding| binding.matches(device)))
+    }
+
+    /// The sink a device should control: its binding's if it sets one, the configured sink
+    /// otherwise.
+    pub fn sink_for(&self, device: Option<&DeviceId>) -> Option<String> {
+        self.binding_for(device)
+            .and_then(|binding| binding.sink.clone())
+            .or_else(|| self.volume.sink.clone())
+    }
+
+    /// The volume curve for a device, picked like its sink.
+    pub fn curve_for(&self, device: Option<&DeviceId>) -> Curve {
+        self.binding_for(device)
+            .and_then(|binding| binding.curve)
+            .unwrap_or(self.volume.curve)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.check().map_err(|invalid| invalid.message)
+    }
+
+    fn check(&self) -> Result<(), Invalid> {
+        rules::validate(&self.rules)
+            .map_err(|error| Invalid::at(&[], error.to_string()).within(&["rule"], error.index))?;
+        self.equalizer.validate()?;
+        self.acceleration.validate()?;
+        self.mappings.validate()?;
+        if self.mappings.uses_equalizer() && !audio::has_equalizer(&self.backend.name) {
+            return Err(Invalid::at(
+                &["mappings"],
+                format!(
+                    "mappings: the {} backend has no equalizer",
+                    self.backend.name
+                ),
+            ));
+        }
+        for (index, binding) in self.bindings.iter().enumerate() {
+            let invalid = |message: &str| {
+                Invalid::at(&[], format!("binding {}: {}", index + 1, message))
+                    .within(&["binding"], index)
+            };
+            if binding.serial.is_none() && binding.path.is_none() {
+                return Err(invalid("needs a serial or a path"));
+            }
+            if binding.sink.is_none() && binding.curve.is_none() {
+                return Err(invalid("sets neither a sink nor a curve"));
+            }
+        }
+        if !BACKENDS.contains(&self.backend.name.as_str()) {
+            return Err(Invalid::at(
+                &["backend", "name"],
+                format!(
+                    "backend.name: unknown backend `{}`, expected one of {}",
+                    self.backend.name,
+                    BACKENDS.join(", ")
+                ),
+            ));
+        }
+        if self.notifications.timeout_ms < -1 {
+            return Err(Invalid::at(
+                &["notifications", "timeout_ms"],
+                format!(
+                    "notifications.timeout_ms: {} must be -1 or more",
+                    self.notifications.timeout_ms
+                ),
+            ));
+        }
+        if !(self.volume.step > 0.0 && self.volume.step <= 100.0) {
+            return Err(Invalid::at(
+                &["volume", "step"],
+                format!(
+                    "volume.step: {} must be above 0 and at most 100",
+                    self.volume.step
+                ),
+            ));
+        }
+        if !(self.volume.max_volume > 0.0 && self.volume.max_volume <= 150.0) {
+            return Err(Invalid::at(
+                &["volume", "max_volume"],
+                format!(
+                    "volume.max_volume: {} must be above 0 and at most 150",
+                    self.volume.max_volume
+                ),
+            ));
+        }
+        if !(-100.0..=100.0).contains(&self.volume.balance) {
+            return Err(Invalid::at(
+                &["volume", "balance"],
+                format!(
+                    "volume.balance: {} must be between -100 and 100",
+                    self.volume.balance
+                ),
+            ));
+        }
+        if !(1..=32).contains(&self.backend.memory_channels) {
+            return Err(Invalid::at(
+                &["backend", "memory_channels"],
+                format!(
+                    "backend.memory_channels: {} must be between 1 and 32",
+                    self.backend.memory_channels
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::rules::{ByteMatch, Message};
+
+    /// Writes `contents` to a config file of its own, named after `name`.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("nommo-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Loads `contents` as the config file, with `flags` on the command line.
+    fn load(name: &str, contents: &str, flags: &[&str]) -> Result<Config, String> {
+        let path = config_file(name, contents);
+        let mut argv = vec!["nommo_vol_driver", "--config", path.to_str().unwrap()];
+        argv.extend_from_slice(flags);
+        let config = Config::load(&Args::parse_from(argv));
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    fn parse_error(contents: &str) -> String {
+        Config::parse(contents).unwrap_err()
+    }
+
+    const FILE: &str = r#"
+[device]
+vid = 0x1532
+pid = 0x0517
+
+[backend]
+name = "memory"
+
+[volume]
+step = 3.0
+max_volume = 80.0
+sink = "speakers"
+"#;
+
+    #[test]
+    fn flags_override_the_file() {
+        let config = load(
+            "flags",
+            FILE,
+            &[
+                "--vid",
+                "0x1234",
+                "--wait",
+                "--step",
+                "2",
+                "--max-volume",
+                "120",
+                "--sink",
+                "headphones",
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.device.vid, Some(0x1234));
+        assert!(config.device.wait);
+        assert_eq!(config.volume.step, 2.0);
+        assert_eq!(config.volume.max_volume, 120.0);
+        assert_eq!(config.volume.sink.as_deref(), Some("headphones"));
+        // what no flag sets comes from the file
+        assert_eq!(config.device.pid, Some(0x0517));
+        assert_eq!(config.backend.name, "memory");
+    }
+
+    #[test]
+    fn the_file_applies_without_flags() {
+        let config = load("no-flags", FILE, &[]).unwrap();
+        assert_eq!(config.device.vid, Some(0x1532));
+        assert!(!config.device.wait);
+        assert_eq!(config.volume.step, 3.0);
+        assert_eq!(config.volume.max_volume, 80.0);
+        assert_eq!(config.volume.sink.as_deref(), Some("speakers"));
+        // and defaults fill in the rest
+        assert_eq!(config.mute, MuteConfig::default());
+    }
+
+    #[test]
+    fn printed_configs_parse_back() {
+        let mut config = Config::default();
+        config.backend.name = String::from("memory");
+        config.volume.curve = Curve::Db;
+        config.volume.sink = Some(String::from("speakers"));
+        config.mappings.eq_value = Action::Equalizer;
+        config.mappings.eq_ranges = vec![EqBinding {
+            min: 4,
+            max: 7,
+            action: Action::SwitchSink(String::from("headphones")),
+        }];
+        config.mappings.buttons = vec![ButtonBinding {
+            button: 1,
+            action: Action::Media(MediaCommand::PlayPause),
+        }];
+        config.equalizer.presets = vec![EqPreset {
+            values: vec![0, 1],
+            bass: 6.0,
+            treble: -2.5,
+        }];
+        config.bindings = vec![Binding {
+            serial: Some(String::from("PM2012H12345678")),
+            path: None,
+            sink: None,
+            curve: Some(Curve::Linear),
+        }];
+        config.rules = vec![Rule {
+            message: Message::Button,
+            report_id: Some(6),
+            bytes: vec![ByteMatch {
+                offset: 0,
+                value: 0x10,
+                mask: 0xf0,
+            }],
+            value: Some(rules::Field {
+                offset: 1,
+                mask: 0xff,
+                shift: 0,
+            }),
+            samples: vec![String::from("06 10 02")],
+        }];
+
+        for config in [Config::default(), config] {
+            let printed = config.to_toml().unwrap();
+            assert_eq!(Config::parse(&printed), Ok(config), "{}", printed);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_name_the_line_and_key() {
+        let error = parse_error("[volume]\nstep = 5.0\nloudness = 3\n");
+        assert!(error.contains("line 3"), "{}", error);
+        assert!(error.contains("loudness"), "{}", error);
+
+        let error = parse_error("[volume]\n\nstep = \"five\"\n");
+        assert!(error.contains("line 3"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_values_name_the_line_and_key() {
+        assert_eq!(
+            parse_error("[mute]\nmute_at_zero = false\n\n[volume]\nstep = 0.0\n"),
+            "line 5: volume.step: 0 must be above 0 and at most 100"
+        );
+        assert_eq!(
+            parse_error("volume.max_volume = 200.0\n"),
+            "line 1: volume.max_volume: 200 must be above 0 and at most 150"
+        );
+        assert_eq!(
+            parse_error("[backend]\nname = \"memory\"\nmemory_channels = 0\n"),
+            "line 3: backend.memory_channels: 0 must be between 1 and 32"
+        );
+    }
+
+    #[test]
+    fn invalid_tables_name_the_line_of_their_entry() {
+        let bindings = r#"
+[[binding]]
+serial = "A"
+sink = "speakers"
+
+[[binding]]
+sink = "headphones"
+"#;
+        assert_eq!(
+            parse_error(bindings),
+            "line 6: binding 2: needs a serial or a path"
+        );
+
+        let presets = r#"
+[backend]
+name = "memory"
+
+[[equalizer.preset]]
+values = [0]
+
+[[equalizer.preset]]
+values = [1]
+bass = 30.0
+"#;
+        assert_eq!(
+            parse_error(presets),
+            "line 10: equalizer.preset.bass: 30 must be between -24 and 24 dB"
+        );
+
+        let levels = r#"
+[acceleration]
+enabled = true
+
+[[acceleration.level]]
+within_ms = 100
+step = 0.0
+"#;
+        assert_eq!(
+            parse_error(levels),
+            "line 7: acceleration: step 0 must be above 0 and at most 100"
+        );
+
+        let rules = r#"
+[[rule]]
+message = "vol-up"
+match = [{ offset = 0, value = 0x02 }]
+
+[[rule]]
+message = "vol-down"
+match = [{ offset = 0, value = 0x02 }]
+samples = ["02 00"]
+"#;
+        assert_eq!(
+            parse_error(rules),
+            "line 6: rule 2: sample `02 00` is decoded by rule 1"
+        );
+    }
+
+    #[test]
+    fn values_set_outside_a_file_have_no_line() {
+        let mut config = Config::default();
+        config.volume.step = 0.0;
+        assert_eq!(
+            config.validate(),
+            Err(String::from(
+                "volume.step: 0 must be above 0 and at most 100"
+            ))
+        );
+    }
+
+    #[test]
+    fn file_errors_name_the_file() {
+        let error = load("bad-file", "[volume]\nstep = -1.0\n", &[]).unwrap_err();
+        assert!(
+            error.ends_with(".toml: line 2: volume.step: -1 must be above 0 and at most 100"),
+            "{}",
+            error
+        );
+    }
+
+    /// Equalizer presets for the memory backend, which has an equalizer.
+    fn presets(presets: &str) -> String {
+        format!("[backend]\nname = \"memory\"\n{}", presets)
+    }
+
+    #[test]
+    fn equalizer_presets_need_values() {
+        let presets = presets("\n[[equalizer.preset]]\nvalues = []\nbass = 3.0\n");
+        assert_eq!(
+            parse_error(&presets),
+            "line 5: equalizer.preset: values must not be empty"
+        );
+    }
+
+    #[test]
+    fn eq_values_are_in_one_preset_only() {
+        let presets = presets(
+            "
+[[equalizer.preset]]
+values = [0, 1]
+bass = 3.0
+
+[[equalizer.preset]]
+values = [2, 1]
+treble = 3.0
+",
+        );
+        assert_eq!(
+            parse_error(&presets),
+            "line 9: equalizer.preset: EQ value 1 is in more than one preset"
+        );
+    }
+
+    #[test]
+    fn equalizer_gains_stay_within_24_db() {
+        let presets_with = |bass: f64, treble: f64| {
+            presets(&format!(
+                "\n[[equalizer.preset]]\nvalues = [0]\nbass = {:?}\ntreble = {:?}\n",
+                bass, treble
+            ))
+        };
+        let config = Config::parse(&presets_with(-24.0, 24.0)).unwrap();
+        assert_eq!(
+            config.equalizer.curve(0),
+            Some(EqCurve {
+                bass: -24.0,
+                treble: 24.0
+            })
+        );
+        assert_eq!(config.equalizer.curve(1), None);
+        assert_eq!(
+            parse_error(&presets_with(-24.5, 0.0)),
+            "line 6: equalizer.preset.bass: -24.5 must be between -24 and 24 dB"
+        );
+        assert_eq!(
+            parse_error(&presets_with(0.0, 24.5)),
+            "line 7: equalizer.preset.treble: 24.5 must be between -24 and 24 dB"
+        );
+    }
+}
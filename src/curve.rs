@@ -0,0 +1,116 @@
+use libpulse_binding::volume::{Volume, VolumeDB, VolumeLinear, VOLUME_MUTED, VOLUME_NORM};
+use serde::{Deserialize, Serialize};
+
+/// Loudness at the bottom of the `db` curve, just above silence.
+const DB_RANGE: f64 = 60.0;
+
+/// How knob positions, in fractions of 100%, map to sink volumes. Volume steps are even in
+/// positions, so the curve decides how loud each step sounds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Curve {
+    /// Positions are linear amplitude factors, so steps sound much larger at low volume.
+    Linear,
+    /// Positions are PulseAudio's volume percentages, as shown by its mixers: amplitude grows
+    /// with the cube of the position.
+    #[default]
+    Cubic,
+    /// Positions are even steps in dB, from -60 dB above 0% to 0 dB at 100%.
+    Db,
+}
+
+impl Curve {
+    /// The sink volume at `position`; positions of 0 or less are silent.
+    pub fn volume(self, position: f64) -> Volume {
+        if position <= 0.0 {
+            return VOLUME_MUTED;
+        }
+        match self {
+            Curve::Linear => Volume::from(VolumeLinear(position)),
+            Curve::Cubic => Volume((position * f64::from(VOLUME_NORM.0)).round() as u32),
+            Curve::Db => Volume::from(VolumeDB((position - 1.0) * DB_RANGE)),
+        }
+    }
+
+    /// The position of a sink volume, the inverse of `volume`.
+    pub fn position(self, volume: Volume) -> f64 {
+        if volume.is_muted() {
+            return 0.0;
+        }
+        match self {
+            Curve::Linear => VolumeLinear::from(volume).0,
+            Curve::Cubic => f64::from(volume.0) / f64::from(VOLUME_NORM.0),
+            Curve::Db => (1.0 + VolumeDB::from(volume).0 / DB_RANGE).max(0.0),
+        }
+    }
+
+    /// Moves `volume` by `delta` positions; raising it stops at `limit`.
+    pub fn step(self, volume: Volume, delta: f64, limit: Volume) -> Volume {
+        let stepped = self.volume(self.position(volume) + delta);
+        if delta > 0.0 && stepped > limit {
+            limit
+        } else {
+            stepped
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [Curve; 3] = [Curve::Linear, Curve::Cubic, Curve::Db];
+
+    #[test]
+    fn maps_the_ends_to_silence_and_normal_volume() {
+        for curve in CURVES {
+            assert_eq!(curve.volume(0.0), VOLUME_MUTED, "{:?}", curve);
+            assert_eq!(curve.volume(-0.1), VOLUME_MUTED, "{:?}", curve);
+            assert_eq!(curve.volume(1.0), VOLUME_NORM, "{:?}", curve);
+            assert_eq!(curve.position(VOLUME_MUTED), 0.0, "{:?}", curve);
+            assert_eq!(curve.position(VOLUME_NORM), 1.0, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn maps_the_middle_by_curve() {
+        // half the amplitude, half of PulseAudio's percentage, and -30 dB
+        assert_eq!(Curve::Linear.volume(0.5), Volume(52016));
+        assert_eq!(Curve::Cubic.volume(0.5), Volume(32768));
+        assert_eq!(Curve::Db.volume(0.5), Volume(20724));
+        for curve in CURVES {
+            assert!((curve.position(curve.volume(0.5)) - 0.5).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        for curve in CURVES {
+            for step in 1..=40 {
+                let position = f64::from(step) / 20.0;
+                let back = curve.position(curve.volume(position));
+                assert!(
+                    (back - position).abs() < 1e-3,
+                    "{:?}: {} came back as {}",
+                    curve,
+                    position,
+                    back
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stops_raising_at_the_limit() {
+        let limit = Volume(40000);
+        for curve in CURVES {
+            let volume = curve.volume(curve.position(limit) - 0.01);
+            assert_eq!(curve.step(volume, 0.05, limit), limit, "{:?}", curve);
+            assert_eq!(curve.step(limit, 0.05, limit), limit, "{:?}", curve);
+            // lowering is never held up, even from above the limit
+            let loud = curve.volume(0.9);
+            assert!(curve.step(loud, -0.05, limit) > limit, "{:?}", curve);
+            assert_eq!(curve.step(curve.volume(0.02), -0.05, limit), VOLUME_MUTED);
+        }
+    }
+}
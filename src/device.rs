@@ -0,0 +1,229 @@
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hidapi::{DeviceInfo, HidApi, HidError, HidResult};
+
+use crate::report::ReportSource;
+
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Identifies one HID interface, for binding it to a sink and telling handlers apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub path: String,
+    pub serial: Option<String>,
+}
+
+/// One HID interface found by a scan, with what profiles are matched on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub id: DeviceId,
+    pub vid: u16,
+    pub pid: u16,
+    /// HID usage page of the interface's first collection, or 0 if it could not be found out.
+    pub usage_page: u16,
+    /// USB interface number, or -1 if the platform does not report it.
+    pub number: i32,
+}
+
+impl From<&DeviceInfo> for Interface {
+    fn from(info: &DeviceInfo) -> Self {
+        Interface {
+            id: DeviceId {
+                path: info.path().to_string_lossy().into_owned(),
+                serial: info
+                    .serial_number()
+                    .filter(|serial| !serial.is_empty())
+                    .map(String::from),
+            },
+            vid: info.vendor_id(),
+            pid: info.product_id(),
+            usage_page: info.usage_page(),
+            number: info.interface_number(),
+        }
+    }
+}
+
+/// Lists the HID interfaces plugged in and opens them, so the supervisor can be driven without
+/// hardware.
+pub trait DeviceBus {
+    /// The interfaces plugged in right now.
+    fn scan(&mut self) -> HidResult<Vec<Interface>>;
+
+    fn open(&mut self, interface: &Interface) -> HidResult<Box<dyn ReportSource>>;
+}
+
+/// Finds and opens devices through hidapi.
+pub struct HidBus {
+    api: HidApi,
+}
+
+impl HidBus {
+    pub fn new() -> HidResult<Self> {
+        Ok(HidBus {
+            api: HidApi::new()?,
+        })
+    }
+}
+
+impl DeviceBus for HidBus {
+    fn scan(&mut self) -> HidResult<Vec<Interface>> {
+        self.api.refresh_devices()?;
+        Ok(self
+            .api
+            .device_list()
+            .map(|info| {
+                let mut interface = Interface::from(info);
+                // hidapi only reports usage pages on some platforms
+                if interface.usage_page == 0 {
+                    interface.usage_page = sysfs_usage_page(&interface.id.path).unwrap_or(0);
+                }
+                interface
+            })
+            .collect())
+    }
+
+    fn open(&mut self, interface: &Interface) -> HidResult<Box<dyn ReportSource>> {
+        let path = CString::new(interface.id.path.as_str()).map_err(|_| HidError::HidApiError {
+            message: format!("Invalid device path {}", interface.id.path),
+        })?;
+        Ok(Box::new(self.api.open_path(&path)?))
+    }
+}
+
+/// The usage page of a HID interface, read from its report descriptor in sysfs. Takes a
+/// `/dev/hidrawN` path or a path of hidapi's libusb backend.
+fn sysfs_usage_page(path: &str) -> Option<u16> {
+    let descriptor = match path.strip_prefix("/dev/") {
+        Some(node) => Path::new("/sys/class/hidraw")
+            .join(node)
+            .join("device/report_descriptor"),
+        None => libusb_descriptor(path)?,
+    };
+    first_usage_page(&fs::read(descriptor).ok()?)
+}
+
+/// Where sysfs keeps the report descriptor of the interface at a libusb path.
+fn libusb_descriptor(path: &str) -> Option<PathBuf> {
+    let (bus, address, number) = parse_libusb_path(path)?;
+    let devices = Path::new("/sys/bus/usb/devices");
+    let read_number = |dir: &Path, file: &str| -> Option<u32> {
+        fs::read_to_string(dir.join(file)).ok()?.trim().parse().ok()
+    };
+    let device = fs::read_dir(devices)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|dir| {
+            read_number(dir, "busnum") == Some(bus) && read_number(dir, "devnum") == Some(address)
+        })?;
+    // the interface is `<device>:<configuration>.<number>`, with its HID device inside
+    let prefix = format!("{}:", device.file_name()?.to_str()?);
+    let suffix = format!(".{}", number);
+    let interface = fs::read_dir(devices).ok()?.flatten().find(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        name.starts_with(&prefix) && name.ends_with(&suffix)
+    })?;
+    fs::read_dir(interface.path())
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path().join("report_descriptor"))
+        .find(|descriptor| descriptor.exists())
+}
+
+/// Bus number, device address and interface number from a libusb path like `0001:0004:03`.
+fn parse_libusb_path(path: &str) -> Option<(u32, u32, u32)> {
+    let parts = path
+        .split(':')
+        .map(|part| u32::from_str_radix(part, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [bus, address, number] => Some((bus, address, number)),
+        _ => None,
+    }
+}
+
+/// The value of the first Usage Page item of a HID report descriptor.
+fn first_usage_page(descriptor: &[u8]) -> Option<u16> {
+    let mut at = 0;
+    while let Some(&prefix) = descriptor.get(at) {
+        if prefix == 0xfe {
+            // a long item, with its data size in the next byte and its tag after that
+            at += 3 + usize::from(*descriptor.get(at + 1)?);
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            size => usize::from(size),
+        };
+        let data = descriptor.get(at + 1..at + 1 + size)?;
+        // global item with tag 0
+        if prefix & 0xfc == 0x04 {
+            let value = data
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | u32::from(*byte));
+            return Some(value as u16);
+        }
+        at += 1 + size;
+    }
+    None
+}
+
+/// Exponential backoff between reconnect attempts, capped at `MAX_RETRY_DELAY`.
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff {
+            delay: MIN_RETRY_DELAY,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt and doubles the following one.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_first_usage_page_of_a_descriptor() {
+        // consumer control, then a vendor-defined collection
+        let consumer = [
+            0x05, 0x0c, 0x09, 0x01, 0xa1, 0x01, 0x85, 0x01, 0xc0, 0x06, 0x00, 0xff,
+        ];
+        assert_eq!(first_usage_page(&consumer), Some(0x0c));
+        let vendor = [0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01];
+        assert_eq!(first_usage_page(&vendor), Some(0xff00));
+        // a long item is skipped whole
+        let long = [0xfe, 0x02, 0x10, 0x05, 0x0c, 0x05, 0x01];
+        assert_eq!(first_usage_page(&long), Some(0x01));
+    }
+
+    #[test]
+    fn finds_no_usage_page_in_a_cut_off_descriptor() {
+        assert_eq!(first_usage_page(&[]), None);
+        assert_eq!(first_usage_page(&[0x09, 0x01, 0xa1, 0x01]), None);
+        assert_eq!(first_usage_page(&[0x06, 0x00]), None);
+    }
+
+    #[test]
+    fn parses_libusb_paths() {
+        assert_eq!(parse_libusb_path("0001:0004:03"), Some((1, 4, 3)));
+        assert_eq!(parse_libusb_path("0003:001a:0b"), Some((3, 26, 11)));
+        assert_eq!(parse_libusb_path("/dev/hidraw0"), None);
+        assert_eq!(parse_libusb_path("0001:0004"), None);
+    }
+}
@@ -0,0 +1,1436 @@
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hidapi::HidError;
+
+use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
+
+use crate::accel::Accelerator;
+use crate::audio::{AudioBackend, AudioError, Sink};
+use crate::config::{AccelerationConfig, Action, Config, EqualizerConfig, Mappings};
+use crate::curve::Curve;
+use crate::device::DeviceId;
+use crate::error::{Error, Policy};
+use crate::media::MediaClient;
+use crate::notify::Notifier;
+use crate::reload::ConfigWatcher;
+use crate::report::{Read, ReplaySource, ReportSource};
+use crate::rules::{self, DecodeError, Rule};
+use crate::service::Service;
+use crate::shutdown::Shutdown;
+use crate::NommoMsg;
+
+/// Room for the largest high-speed interrupt report plus its report ID; a read filling all of it
+/// may have been cut off.
+const REPORT_BUFFER_LEN: usize = 1025;
+/// How often waiting loops wake up to check for a shutdown or a configuration change.
+pub const TICK: Duration = Duration::from_millis(200);
+
+fn volume_from_percent(delta: f64) -> Volume {
+    let vol_raw = (delta * 100.0) * (f64::from(VOLUME_NORM.0) / 100.0);
+    Volume(vol_raw as u32)
+}
+
+/// Runtime settings the event loop works with; `step` and `max_volume` are fractions of 100%.
+pub struct Settings {
+    step: f64,
+    max_volume: f64,
+    /// From -1 (left only) to 1 (right only).
+    balance: f32,
+    curve: Curve,
+    acceleration: AccelerationConfig,
+    coalesce: Duration,
+    sink: Option<String>,
+    mute_at_zero: bool,
+    unmute_on_raise: bool,
+    mappings: Mappings,
+    equalizer: EqualizerConfig,
+    player: Option<String>,
+    rules: Vec<Rule>,
+    verbose: bool,
+    device: Option<DeviceId>,
+}
+
+impl Settings {
+    /// Settings for the given device, picking up its sink binding if there is one.
+    pub fn new(config: &Config, verbose: bool, device: Option<DeviceId>) -> Self {
+        Settings {
+            step: config.volume.step / 100.0,
+            max_volume: config.volume.max_volume / 100.0,
+            balance: (config.volume.balance / 100.0) as f32,
+            curve: config.curve_for(device.as_ref()),
+            acceleration: config.acceleration.clone(),
+            coalesce: Duration::from_millis(config.volume.coalesce_ms),
+            sink: config.sink_for(device.as_ref()),
+            mute_at_zero: config.mute.mute_at_zero,
+            unmute_on_raise: config.mute.unmute_on_raise,
+            mappings: config.mappings.clone(),
+            equalizer: config.equalizer.clone(),
+            player: config.media.player.clone(),
+            rules: config.rules.clone(),
+            verbose,
+            device,
+        }
+    }
+
+    /// The volume step for `action` at `at`, accelerated if enabled.
+    pub fn step_for(&self, action: &Action, at: Instant, accelerator: &mut Accelerator) -> f64 {
+        let up = match action {
+            Action::VolumeUp => true,
+            Action::VolumeDown => false,
+            _ => return self.step,
+        };
+        if self.acceleration.enabled {
+            accelerator.step(&self.acceleration, up, at) / 100.0
+        } else {
+            self.step
+        }
+    }
+}
+
+/// Whether the knob shifts the balance, and how far it moved it from the configured one.
+#[derive(Debug, Default)]
+pub struct BalanceMode {
+    pub active: bool,
+    shift: f32,
+}
+
+/// What the event loop acts on besides the device.
+pub struct Outputs {
+    pub audio: Box<dyn AudioBackend>,
+    pub media: MediaClient,
+    /// Where sink changes are published, if the D-Bus service is running.
+    pub service: Option<Service>,
+    pub notifier: Option<Notifier>,
+    pub balance: BalanceMode,
+}
+
+impl Outputs {
+    pub fn new(
+        audio: Box<dyn AudioBackend>,
+        service: Option<Service>,
+        notifier: Option<Notifier>,
+    ) -> Self {
+        Outputs {
+            audio,
+            media: MediaClient::default(),
+            service,
+            notifier,
+            balance: BalanceMode::default(),
+        }
+    }
+}
+
+/// Runs `command` through `sh -c` without waiting for it to finish.
+fn run_command(command: &str, msg: &NommoMsg) -> Result<(), String> {
+    let mut child = Command::new("sh");
+    child.arg("-c").arg(command);
+    if let NommoMsg::EqValue(value) | NommoMsg::Button(value) = msg {
+        child.env("NOMMO_VALUE", value.to_string());
+    }
+    let mut child = child
+        .spawn()
+        .map_err(|e| format!("Cannot run `{}`: {}", command, e))?;
+    // reap it in the background so it does not linger as a zombie
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+fn switch_sink(name: &str, audio: &mut dyn AudioBackend) -> Result<(), AudioError> {
+    let sink = audio.find_sink(name)?;
+    audio.set_default_sink(&sink)
+}
+
+/// Sets `volumes` to the configured balance plus the shift made in balance mode. With both at
+/// 0, or without left and right channels, the sink keeps the balance it has.
+fn apply_balance(
+    volumes: &mut ChannelVolumes,
+    sink: &Sink,
+    audio: &mut dyn AudioBackend,
+    balance: f32,
+    shift: f32,
+) -> Result<(), AudioError> {
+    if balance == 0.0 && shift == 0.0 {
+        return Ok(());
+    }
+    let map = audio.channel_map(sink)?;
+    if !map.can_balance() {
+        return Ok(());
+    }
+    volumes
+        .set_balance(&map, (balance + shift).clamp(-1.0, 1.0))
+        .ok_or_else(|| AudioError::Unsupported(String::from("Cannot set balance")))?;
+    Ok(())
+}
+
+/// Carries out the actions acting on the controlled sink: the one set over D-Bus, else the
+/// configured one, else the default sink.
+fn adjust_sink(
+    msg: &NommoMsg,
+    action: &Action,
+    step: f64,
+    outputs: &mut Outputs,
+    settings: &Settings,
+) -> Result<(), AudioError> {
+    let audio = outputs.audio.as_mut();
+    let sink_override = outputs.service.as_ref().and_then(Service::sink_override);
+    let sink = match sink_override.as_ref().or(settings.sink.as_ref()) {
+        Some(name) => audio.find_sink(name)?,
+        None => audio.default_sink()?,
+    };
+    let mut current_volume = audio.volume(&sink)?;
+    let muted = audio.mute(&sink)?;
+
+    match action {
+        Action::VolumeUp | Action::VolumeDown if outputs.balance.active => {
+            let step = match action {
+                Action::VolumeUp => step as f32,
+                _ => -step as f32,
+            };
+            let shift = &mut outputs.balance.shift;
+            *shift = (settings.balance + *shift + step).clamp(-1.0, 1.0) - settings.balance;
+            apply_balance(&mut current_volume, &sink, audio, settings.balance, *shift)?;
+            audio.set_volume(&sink, &current_volume)?;
+        }
+        Action::VolumeUp => {
+            let loudest = settings.curve.step(
+                current_volume.max(),
+                step,
+                volume_from_percent(settings.max_volume),
+            );
+            let volumes = current_volume.scale(loudest).ok_or_else(|| {
+                AudioError::Unsupported(String::from("Cannot set new ChannelVolumes"))
+            })?;
+            let shift = outputs.balance.shift;
+            apply_balance(volumes, &sink, audio, settings.balance, shift)?;
+            audio.set_volume(&sink, volumes)?;
+
+            // if muted, unmute
+            if muted && settings.unmute_on_raise {
+                audio.set_mute(&sink, false)?;
+            }
+        }
+        Action::VolumeDown => {
+            let previous_volume = current_volume;
+            let loudest = settings.curve.step(
+                current_volume.max(),
+                -step,
+                volume_from_percent(settings.max_volume),
+            );
+            let volumes = current_volume.scale(loudest).ok_or_else(|| {
+                AudioError::Unsupported(String::from("Cannot set new ChannelVolumes"))
+            })?;
+            let shift = outputs.balance.shift;
+            apply_balance(volumes, &sink, audio, settings.balance, shift)?;
+            audio.set_volume(&sink, volumes)?;
+
+            // if volume at 0%, mute
+            if settings.mute_at_zero && previous_volume == volume_from_percent(0.0) && !muted {
+                audio.set_mute(&sink, true)?;
+            }
+        }
+        Action::ToggleMute => audio.set_mute(&sink, !muted)?,
+        Action::Equalizer => {
+            if let NommoMsg::EqValue(value) = msg {
+                match settings.equalizer.curve(*value) {
+                    Some(curve) => audio.set_equalizer(&sink, &curve)?,
+                    None if settings.verbose => {
+                        eprintln!("No equalizer preset for EQ value {}", value)
+                    }
+                    None => {}
+                }
+            }
+        }
+        Action::Command(_)
+        | Action::SwitchSink(_)
+        | Action::Media(_)
+        | Action::ToggleBalance
+        | Action::Ignore => {}
+    }
+
+    let notifier = outputs.notifier.as_ref().filter(|_| {
+        matches!(
+            action,
+            Action::VolumeUp | Action::VolumeDown | Action::ToggleMute
+        )
+    });
+    if settings.verbose || outputs.service.is_some() || notifier.is_some() {
+        let (volumes, muted) = (audio.volume(&sink)?, audio.mute(&sink)?);
+        if settings.verbose {
+            eprintln!(
+                "{}: volume {}, muted: {}",
+                sink.name,
+                volumes.print(),
+                muted
+            );
+        }
+        if let Some(service) = &outputs.service {
+            service.update(&sink.name, &volumes, muted);
+        }
+        if let Some(notifier) = notifier {
+            notifier.volume_changed(&sink.name, &volumes, muted);
+        }
+    }
+    Ok(())
+}
+
+/// Carries out `action`, triggered by `msg`; volume actions move by `step`, a fraction of 100%.
+pub fn dispatch(
+    msg: &NommoMsg,
+    action: &Action,
+    step: f64,
+    outputs: &mut Outputs,
+    settings: &Settings,
+) -> Result<(), Error> {
+    match action {
+        Action::Ignore => Ok(()),
+        Action::ToggleBalance => {
+            outputs.balance.active = !outputs.balance.active;
+            eprintln!(
+                "Balance mode {}",
+                if outputs.balance.active { "on" } else { "off" }
+            );
+            Ok(())
+        }
+        Action::Command(command) => run_command(command, msg).map_err(Error::Action),
+        Action::SwitchSink(name) => switch_sink(name, outputs.audio.as_mut()).map_err(Error::Audio),
+        Action::Media(command) => outputs
+            .media
+            .send(*command, settings.player.as_deref())
+            .map_err(Error::Action),
+        _ => adjust_sink(msg, action, step, outputs, settings).map_err(Error::Audio),
+    }
+}
+
+/// Decodes a report; one filling the whole read buffer may have been cut off.
+fn decode_report(report: &[u8], settings: &Settings, builtin: &[Rule]) -> Result<NommoMsg, Error> {
+    if report.len() == REPORT_BUFFER_LEN {
+        return Err(DecodeError::Truncated { len: report.len() }.into());
+    }
+    let msg = rules::decode(settings.rules.iter().chain(builtin), report)?;
+    Ok(msg.unwrap_or(NommoMsg::Noop))
+}
+
+/// Volume turns read within the coalescing window of the first one.
+struct Burst {
+    /// When the first turn arrived, on the clock of the report source.
+    started: Instant,
+    /// When the turns are applied at the latest if no other report comes in, on the real clock.
+    due: Instant,
+    /// Sum of the turns' steps, negative for turning down.
+    step: f64,
+    turns: usize,
+}
+
+impl Burst {
+    /// Applies the burst as a single volume change; turns cancelling out change nothing.
+    fn apply(self, outputs: &mut Outputs, settings: &Settings) -> Result<(), Error> {
+        if settings.verbose && self.turns > 1 {
+            eprintln!("{} turns coalesced: {:+.1}%", self.turns, self.step * 100.0);
+        }
+        let action = if self.step > 0.0 {
+            Action::VolumeUp
+        } else if self.step < 0.0 {
+            Action::VolumeDown
+        } else {
+            return Ok(());
+        };
+        dispatch_retrying(&NommoMsg::Noop, &action, self.step.abs(), outputs, settings)
+    }
+}
+
+/// Dispatches `action`, retrying once if the error calls for it.
+fn dispatch_retrying(
+    msg: &NommoMsg,
+    action: &Action,
+    step: f64,
+    outputs: &mut Outputs,
+    settings: &Settings,
+) -> Result<(), Error> {
+    dispatch(msg, action, step, outputs, settings).or_else(|error| match error.policy() {
+        Policy::Retry => {
+            eprintln!("{}, retrying", error);
+            dispatch(msg, action, step, outputs, settings)
+        }
+        _ => Err(error),
+    })
+}
+
+/// Passes on errors that end the connection, logging the ones that only skip a report.
+fn report_result(result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+        Err(error) if error.policy() == Policy::Exit => Err(error),
+        Err(error) => {
+            eprintln!("Skipping report: {}", error);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+/// What makes the event loop reload its configuration or stop, besides its device.
+#[derive(Clone)]
+pub struct Control {
+    pub watcher: ConfigWatcher,
+    pub shutdown: Shutdown,
+}
+
+/// What the reader thread hands to the event loop.
+enum Event {
+    Report {
+        report: Vec<u8>,
+        at: Instant,
+    },
+    /// The source ran out of reports, or failed.
+    Closed(Result<(), HidError>),
+}
+
+/// Reads a report whenever the event loop asks for one, on a thread of its own, so the event
+/// loop can wait for reports, timers and control changes at once. Reading only on request
+/// keeps replays in step with the event loop. Ends when the source closes, the event loop
+/// stops asking or `stop` is requested.
+fn read_reports(
+    mut source: Box<dyn ReportSource>,
+    requests: Receiver<()>,
+    events: Sender<Event>,
+    stop: Shutdown,
+) {
+    let mut buff = vec![0_u8; REPORT_BUFFER_LEN];
+    while requests.recv().is_ok() {
+        let event = loop {
+            if stop.requested() {
+                return;
+            }
+            match source.read_report(&mut buff, TICK) {
+                Ok(Read::Report(len)) => {
+                    break Event::Report {
+                        report: buff[..len].to_vec(),
+                        at: source.received_at(),
+                    }
+                }
+                Ok(Read::TimedOut) => {}
+                Ok(Read::Closed) => break Event::Closed(Ok(())),
+                Err(error) => break Event::Closed(Err(error)),
+            }
+        };
+        let closed = matches!(event, Event::Closed(_));
+        if events.send(event).is_err() || closed {
+            return;
+        }
+    }
+}
+
+/// Publishes the changes other applications made to the default sink, if that is the sink
+/// the driver controls.
+fn publish_changes(outputs: &mut Outputs, settings: &Settings) {
+    let service = match &outputs.service {
+        Some(service) if settings.sink.is_none() && service.sink_override().is_none() => service,
+        _ => return,
+    };
+    match outputs.audio.changes() {
+        Ok(Some(state)) => service.update(&state.sink.name, &state.volumes, state.mute),
+        Ok(None) => {}
+        Err(error) if settings.verbose => eprintln!("Cannot follow sink changes: {}", error),
+        Err(_) => {}
+    }
+}
+
+/// The per-device event loop state besides its settings.
+struct EventLoop<'a> {
+    builtin: &'a [Rule],
+    accelerator: Accelerator,
+    burst: Option<Burst>,
+}
+
+impl EventLoop<'_> {
+    /// Applies the pending turns, if any.
+    fn flush(&mut self, outputs: &mut Outputs, settings: &Settings) -> Result<(), Error> {
+        match self.burst.take() {
+            Some(burst) => report_result(burst.apply(outputs, settings)),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_report(
+        &mut self,
+        report: &[u8],
+        at: Instant,
+        outputs: &mut Outputs,
+        settings: &Settings,
+    ) -> Result<(), Error> {
+        if self
+            .burst
+            .as_ref()
+            .is_some_and(|burst| at.saturating_duration_since(burst.started) > settings.coalesce)
+        {
+            self.flush(outputs, settings)?;
+        }
+        let msg = decode_report(report, settings, self.builtin)?;
+        let action = settings.mappings.action(&msg);
+        let step = settings.step_for(action, at, &mut self.accelerator);
+        if settings.verbose {
+            eprintln!("{:?}: {:?}", msg, action);
+        }
+        let step = match action {
+            Action::VolumeUp => step,
+            Action::VolumeDown => -step,
+            _ => {
+                self.flush(outputs, settings)?;
+                return dispatch_retrying(&msg, action, step, outputs, settings);
+            }
+        };
+        if settings.coalesce.is_zero() {
+            return dispatch_retrying(&msg, action, step.abs(), outputs, settings);
+        }
+        let burst = self.burst.get_or_insert(Burst {
+            started: at,
+            due: Instant::now() + settings.coalesce,
+            step: 0.0,
+            turns: 0,
+        });
+        burst.step += step;
+        burst.turns += 1;
+        Ok(())
+    }
+
+    /// Handles events until the reader closes or a shutdown is requested, asking the reader
+    /// for the next report after each one.
+    fn run(
+        &mut self,
+        reads: &Sender<()>,
+        events: &Receiver<Event>,
+        outputs: &mut Outputs,
+        settings: &mut Settings,
+        control: &mut Control,
+    ) -> Result<(), Error> {
+        loop {
+            if control.shutdown.requested() {
+                return self.flush(outputs, settings);
+            }
+            match control.watcher.poll() {
+                Some(Ok(config)) => {
+                    *settings = Settings::new(&config, settings.verbose, settings.device.take());
+                    eprintln!("Configuration reloaded");
+                }
+                Some(Err(error)) => {
+                    eprintln!("Config error, keeping previous configuration: {}", error);
+                }
+                None => {}
+            }
+            publish_changes(outputs, settings);
+
+            // wake up when the pending turns are due, and now and then to check for control
+            // changes
+            let timeout = match &self.burst {
+                Some(burst) => burst
+                    .due
+                    .saturating_duration_since(Instant::now())
+                    .min(TICK),
+                None => TICK,
+            };
+            match events.recv_timeout(timeout) {
+                Ok(Event::Report { report, at }) => {
+                    let result = self.handle_report(&report, at, outputs, settings);
+                    report_result(result)?;
+                    // the reader is gone if it failed; that shows up as a disconnect
+                    let _ = reads.send(());
+                }
+                Ok(Event::Closed(result)) => {
+                    self.flush(outputs, settings)?;
+                    return result.map_err(Error::from);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if self
+                        .burst
+                        .as_ref()
+                        .is_some_and(|burst| burst.due <= Instant::now())
+                    {
+                        self.flush(outputs, settings)?;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return self.flush(outputs, settings),
+            }
+        }
+    }
+}
+
+/// Handles reports, decoded with the configured rules and then the profile's `builtin` ones,
+/// until the source is exhausted or a shutdown is requested, or fails with the error that ended
+/// the connection.
+///
+/// Reports are read on a separate thread, so configuration reloads, shutdowns and changes other
+/// applications make to the default sink take effect without waiting for the next report.
+/// Reports that cannot be decoded are skipped. A volume change that lost the connection to the
+/// sound server is retried once, which reconnects the audio backend, and the report is skipped if
+/// that fails too; one aimed at a missing sink is skipped without reconnecting. With a coalescing
+/// window set, volume turns are summed until the window is over, or another action comes in,
+/// and then applied at once.
+pub fn handle_device(
+    source: Box<dyn ReportSource>,
+    builtin: &[Rule],
+    outputs: &mut Outputs,
+    settings: &mut Settings,
+    control: &mut Control,
+) -> Result<(), Error> {
+    let (reads, requests) = mpsc::channel();
+    let (sender, events) = mpsc::channel();
+    let stop = Shutdown::default();
+    let reader = {
+        let stop = stop.clone();
+        thread::spawn(move || read_reports(source, requests, sender, stop))
+    };
+    // the reader has not started yet, so this cannot fail
+    let _ = reads.send(());
+
+    let mut event_loop = EventLoop {
+        builtin,
+        accelerator: Accelerator::default(),
+        burst: None,
+    };
+    let result = event_loop.run(&reads, &events, outputs, settings, control);
+    stop.request();
+    drop(reads);
+    // the reader notices within a tick
+    let _ = reader.join();
+    result
+}
+
+/// Replays `source` through the event loop, reconnecting to it at once after a replayed
+/// disconnect, until it runs out or a shutdown is requested.
+pub fn run_replay(
+    source: &ReplaySource,
+    builtin: &[Rule],
+    outputs: &mut Outputs,
+    settings: &mut Settings,
+    control: &mut Control,
+) -> Result<(), Error> {
+    loop {
+        match handle_device(
+            Box::new(source.clone()),
+            builtin,
+            outputs,
+            settings,
+            control,
+        ) {
+            Err(error) if error.policy() == Policy::Retry => {
+                eprintln!("{}", error);
+                eprintln!("Device reconnected");
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpulse_binding::volume::VOLUME_MUTED;
+
+    use hidapi::HidResult;
+
+    use super::*;
+    use crate::audio::{Call, EqCurve, OutsideChange, RecordingMixer};
+    use crate::config::{ButtonBinding, EqBinding, EqPreset};
+    use crate::media::MediaCommand;
+    use crate::profile;
+    use crate::report::{ReplayEntry, ReplaySource};
+    use crate::service::PrivateBus;
+
+    const STEP: f64 = 0.05;
+
+    /// A stereo memory sink at `percent`.
+    fn mixer(percent: f64) -> RecordingMixer {
+        RecordingMixer::new(Curve::Cubic.volume(percent / 100.0), 2)
+    }
+
+    fn outputs(mixer: &RecordingMixer) -> Outputs {
+        Outputs::new(Box::new(mixer.connect()), None, None)
+    }
+
+    fn stereo(volume: Volume) -> ChannelVolumes {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set(2, volume);
+        volumes
+    }
+
+    fn at(percent: f64) -> Volume {
+        Curve::Cubic.volume(percent / 100.0)
+    }
+
+    fn run(action: Action, mixer: &RecordingMixer, config: &Config) -> Vec<Call> {
+        let settings = Settings::new(config, false, None);
+        let mut outputs = outputs(mixer);
+        dispatch(&NommoMsg::Noop, &action, STEP, &mut outputs, &settings).unwrap();
+        mixer.take_calls()
+    }
+
+    /// Dispatches `msg` to the action the config maps it to, as the event loop does.
+    fn act(msg: NommoMsg, mixer: &RecordingMixer, config: &Config) -> Result<Vec<Call>, Error> {
+        let settings = Settings::new(config, false, None);
+        let action = settings.mappings.action(&msg);
+        dispatch(&msg, action, STEP, &mut outputs(mixer), &settings)?;
+        Ok(mixer.take_calls())
+    }
+
+    /// The balance of `volumes` on `mixer`'s sink, from -1 (left) to 1 (right).
+    fn balance(mixer: &RecordingMixer, volumes: &ChannelVolumes) -> f32 {
+        let mut backend = mixer.connect();
+        let sink = backend.default_sink().unwrap();
+        volumes.get_balance(&backend.channel_map(&sink).unwrap())
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    fn control() -> Control {
+        Control {
+            watcher: ConfigWatcher::idle(),
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    /// The default configuration, applying every turn on its own rather than coalescing them.
+    fn each_turn() -> Config {
+        let mut config = Config::default();
+        config.volume.coalesce_ms = 0;
+        config
+    }
+
+    /// Runs the event loop over `source` with the default profile's rules.
+    fn replay_to(
+        source: ReplaySource,
+        outputs: &mut Outputs,
+        config: &Config,
+    ) -> Result<(), Error> {
+        let rules = profile::DEFAULT.rules().unwrap();
+        let source = Box::new(source);
+        let mut settings = Settings::new(config, false, None);
+        handle_device(source, &rules, outputs, &mut settings, &mut control())
+    }
+
+    /// Replays `entries` to `mixer`, returning the calls made.
+    fn replay(
+        entries: Vec<ReplayEntry>,
+        mixer: &RecordingMixer,
+        config: &Config,
+    ) -> Result<Vec<Call>, Error> {
+        replay_to(ReplaySource::new(entries), &mut outputs(mixer), config)?;
+        Ok(mixer.take_calls())
+    }
+
+    fn report(bytes: &[u8]) -> ReplayEntry {
+        ReplayEntry::Report(bytes.to_vec())
+    }
+
+    /// Hands out `reports`, then requests a shutdown and waits like an idle device.
+    struct ShuttingDown {
+        reports: Vec<Vec<u8>>,
+        shutdown: Shutdown,
+    }
+
+    impl ReportSource for ShuttingDown {
+        fn read_report(&mut self, buf: &mut [u8], timeout: Duration) -> HidResult<Read> {
+            if self.reports.is_empty() {
+                self.shutdown.request();
+                thread::sleep(timeout);
+                return Ok(Read::TimedOut);
+            }
+            let report = self.reports.remove(0);
+            buf[..report.len()].copy_from_slice(&report);
+            Ok(Read::Report(report.len()))
+        }
+    }
+
+    /// Hands out `reports`, then gets a SIGTERM once and waits like an idle device.
+    struct Terminated {
+        reports: Vec<Vec<u8>>,
+        raised: bool,
+    }
+
+    impl ReportSource for Terminated {
+        fn read_report(&mut self, buf: &mut [u8], timeout: Duration) -> HidResult<Read> {
+            if self.reports.is_empty() {
+                if !self.raised {
+                    self.raised = true;
+                    signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+                }
+                thread::sleep(timeout);
+                return Ok(Read::TimedOut);
+            }
+            let report = self.reports.remove(0);
+            buf[..report.len()].copy_from_slice(&report);
+            Ok(Read::Report(report.len()))
+        }
+    }
+
+    /// One thing a `Reloading` source does when read.
+    enum Step {
+        Report(Vec<u8>),
+        /// Reloads the config before handing out the next report.
+        Reload,
+    }
+
+    /// Hands out reports, reloading the config in between where scripted.
+    struct Reloading {
+        steps: Vec<Step>,
+        watcher: ConfigWatcher,
+    }
+
+    impl ReportSource for Reloading {
+        fn read_report(&mut self, buf: &mut [u8], _timeout: Duration) -> HidResult<Read> {
+            while !self.steps.is_empty() {
+                match self.steps.remove(0) {
+                    Step::Reload => self.watcher.reload(),
+                    Step::Report(report) => {
+                        buf[..report.len()].copy_from_slice(&report);
+                        return Ok(Read::Report(report.len()));
+                    }
+                }
+            }
+            Ok(Read::Closed)
+        }
+    }
+
+    #[test]
+    fn volume_up_raises_every_channel_by_a_step() {
+        let mixer = mixer(50.0);
+        let calls = run(Action::VolumeUp, &mixer, &Config::default());
+        assert_eq!(calls, vec![Call::Volume(stereo(at(55.0)))]);
+        assert_eq!(mixer.volumes(), stereo(at(55.0)));
+    }
+
+    #[test]
+    fn volume_down_lowers_every_channel_by_a_step() {
+        let calls = run(Action::VolumeDown, &mixer(50.0), &Config::default());
+        assert_eq!(calls, vec![Call::Volume(stereo(at(45.0)))]);
+    }
+
+    #[test]
+    fn volume_up_stops_at_max_volume() {
+        let mut config = Config::default();
+        config.volume.max_volume = 52.0;
+        let calls = run(Action::VolumeUp, &mixer(50.0), &config);
+        assert_eq!(calls, vec![Call::Volume(stereo(volume_from_percent(0.52)))]);
+    }
+
+    #[test]
+    fn raising_unmutes() {
+        let mixer = mixer(50.0);
+        mixer.change(OutsideChange::Mute(true));
+        let calls = run(Action::VolumeUp, &mixer, &Config::default());
+        assert_eq!(
+            calls,
+            vec![Call::Volume(stereo(at(55.0))), Call::Mute(false)]
+        );
+    }
+
+    #[test]
+    fn raising_keeps_mute_unless_configured() {
+        let mixer = mixer(50.0);
+        mixer.change(OutsideChange::Mute(true));
+        let mut config = Config::default();
+        config.mute.unmute_on_raise = false;
+        let calls = run(Action::VolumeUp, &mixer, &config);
+        assert_eq!(calls, vec![Call::Volume(stereo(at(55.0)))]);
+        assert!(mixer.mute());
+    }
+
+    #[test]
+    fn turning_down_at_zero_mutes() {
+        let mixer = RecordingMixer::new(VOLUME_MUTED, 2);
+        let calls = run(Action::VolumeDown, &mixer, &Config::default());
+        assert_eq!(calls, vec![Call::Volume(stereo(at(0.0))), Call::Mute(true)]);
+    }
+
+    #[test]
+    fn reaching_zero_does_not_mute_yet() {
+        let calls = run(Action::VolumeDown, &mixer(5.0), &Config::default());
+        assert_eq!(calls, vec![Call::Volume(stereo(at(0.0)))]);
+    }
+
+    #[test]
+    fn toggle_mute_flips_mute() {
+        let mixer = mixer(50.0);
+        assert_eq!(
+            run(Action::ToggleMute, &mixer, &Config::default()),
+            vec![Call::Mute(true)]
+        );
+        assert_eq!(
+            run(Action::ToggleMute, &mixer, &Config::default()),
+            vec![Call::Mute(false)]
+        );
+    }
+
+    #[test]
+    fn replayed_knob_turns_change_the_volume() {
+        let entries = vec![
+            report(&[1, 233, 0, 0]),
+            report(&[1, 233]),
+            report(&[1, 234, 0, 0, 0, 0, 0, 0]),
+        ];
+        let calls = replay(entries, &mixer(50.0), &each_turn()).unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                Call::Volume(stereo(at(55.0))),
+                Call::Volume(stereo(at(60.0))),
+                Call::Volume(stereo(at(55.0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_eq_values_pick_their_binding() {
+        let mut config = Config::default();
+        config.mappings.eq_ranges = vec![EqBinding {
+            min: 2,
+            max: 3,
+            action: Action::ToggleMute,
+        }];
+        let entries = vec![
+            report(&[5, 15, 0, 3, 0, 0]),
+            // outside the range: ignored
+            report(&[5, 15, 0, 7, 0, 0]),
+            report(&[5, 15, 0xff, 2]),
+        ];
+        let calls = replay(entries, &mixer(50.0), &config).unwrap();
+        assert_eq!(calls, vec![Call::Mute(true), Call::Mute(false)]);
+    }
+
+    #[test]
+    fn replayed_reports_decode_whatever_their_length() {
+        let entries = [(8, 233), (16, 234), (64, 233)]
+            .iter()
+            .map(|&(len, usage)| {
+                let mut bytes = vec![1, usage];
+                bytes.resize(len, 0);
+                ReplayEntry::Report(bytes)
+            })
+            .collect();
+        let calls = replay(entries, &mixer(50.0), &each_turn()).unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                Call::Volume(stereo(at(55.0))),
+                Call::Volume(stereo(at(50.0))),
+                Call::Volume(stereo(at(55.0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_filling_the_buffer_may_be_cut_off() {
+        let settings = Settings::new(&Config::default(), false, None);
+        let report = vec![1; REPORT_BUFFER_LEN];
+        assert!(matches!(
+            decode_report(&report, &settings, &[]),
+            Err(Error::Decode(DecodeError::Truncated {
+                len: REPORT_BUFFER_LEN
+            }))
+        ));
+    }
+
+    #[test]
+    fn unknown_reports_change_nothing() {
+        let entries = vec![report(&[1, 0x42]), report(&[9, 233]), report(&[1, 233])];
+        let calls = replay(entries, &mixer(50.0), &Config::default()).unwrap();
+        assert_eq!(calls, vec![Call::Volume(stereo(at(55.0)))]);
+    }
+
+    #[test]
+    fn replay_resumes_after_a_replayed_disconnect() {
+        let source = ReplaySource::new(vec![
+            report(&[1, 233]),
+            ReplayEntry::Disconnect,
+            report(&[1, 233]),
+        ]);
+        let rules = profile::DEFAULT.rules().unwrap();
+        let mixer = mixer(50.0);
+        let mut settings = Settings::new(&Config::default(), false, None);
+        let result = run_replay(
+            &source,
+            &rules,
+            &mut outputs(&mixer),
+            &mut settings,
+            &mut control(),
+        );
+        assert!(result.is_ok());
+        assert_eq!(
+            mixer.take_calls(),
+            vec![
+                Call::Volume(stereo(at(55.0))),
+                Call::Volume(stereo(at(60.0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_volume_change_is_retried_after_reconnecting() {
+        let mixer = mixer(50.0);
+        let (backend, connects) = mixer.reconnecting();
+        let mut outputs = Outputs::new(Box::new(backend), None, None);
+        mixer.fail_calls(1);
+        let source = ReplaySource::new(vec![report(&[1, 233])]);
+        replay_to(source, &mut outputs, &Config::default()).unwrap();
+        assert_eq!(mixer.take_calls(), vec![Call::Volume(stereo(at(55.0)))]);
+        assert_eq!(connects.get(), 2);
+    }
+
+    #[test]
+    fn volume_change_failing_again_skips_the_report() {
+        let mixer = mixer(50.0);
+        let (backend, connects) = mixer.reconnecting();
+        let mut outputs = Outputs::new(Box::new(backend), None, None);
+        mixer.fail_calls(2);
+        let source = ReplaySource::new(vec![report(&[1, 233]), report(&[1, 233])]);
+        replay_to(source, &mut outputs, &each_turn()).unwrap();
+        assert_eq!(mixer.take_calls(), vec![Call::Volume(stereo(at(55.0)))]);
+        // once for the retry, once for the next report
+        assert_eq!(connects.get(), 3);
+    }
+
+    #[test]
+    fn a_missing_sink_skips_the_report_without_reconnecting() {
+        let mixer = mixer(50.0);
+        let (backend, connects) = mixer.reconnecting();
+        let mut outputs = Outputs::new(Box::new(backend), None, None);
+        let mut config = Config::default();
+        config.volume.sink = Some(String::from("hdmi"));
+        let source = ReplaySource::new(vec![report(&[1, 233]), report(&[1, 233])]);
+        replay_to(source, &mut outputs, &config).unwrap();
+        assert_eq!(mixer.take_calls(), vec![]);
+        assert_eq!(connects.get(), 1);
+    }
+
+    #[test]
+    fn undecodable_reports_are_skipped() {
+        let entries = vec![report(&[5, 15, 0]), report(&[]), report(&[1, 233])];
+        let calls = replay(entries, &mixer(50.0), &Config::default()).unwrap();
+        assert_eq!(calls, vec![Call::Volume(stereo(at(55.0)))]);
+    }
+
+    #[test]
+    fn only_exit_errors_end_the_loop() {
+        let error = report_result(Err(Error::Config(String::from("bad")))).unwrap_err();
+        assert_eq!(error.exit_code(), 78);
+        assert!(report_result(Err(DecodeError::Empty.into())).is_ok());
+        assert!(report_result(Err(Error::Action(String::from("failed")))).is_ok());
+        let gone = AudioError::Disconnected(String::from("gone"));
+        assert!(report_result(Err(Error::Audio(gone))).is_ok());
+    }
+
+    #[test]
+    fn maps_knob_turns_to_their_actions() {
+        let mut config = Config::default();
+        config.mappings.vol_up = Action::ToggleMute;
+        config.mappings.vol_down = Action::SwitchSink(String::from("memory"));
+        let mixer = mixer(50.0);
+        assert_eq!(
+            act(NommoMsg::VolUp, &mixer, &config).unwrap(),
+            vec![Call::Mute(true)]
+        );
+        assert_eq!(
+            act(NommoMsg::VolDown, &mixer, &config).unwrap(),
+            vec![Call::DefaultSink]
+        );
+    }
+
+    #[test]
+    fn maps_eq_values_by_range() {
+        let mut config = Config::default();
+        config.mappings.eq_value = Action::Equalizer;
+        config.mappings.eq_ranges = vec![
+            EqBinding {
+                min: 0,
+                max: 1,
+                action: Action::VolumeUp,
+            },
+            EqBinding {
+                min: 2,
+                max: 3,
+                action: Action::Ignore,
+            },
+        ];
+        config.equalizer.presets = vec![EqPreset {
+            values: vec![4],
+            bass: 3.0,
+            treble: -1.5,
+        }];
+        let mixer = mixer(50.0);
+        assert_eq!(
+            act(NommoMsg::EqValue(1), &mixer, &config).unwrap(),
+            vec![Call::Volume(stereo(at(55.0)))]
+        );
+        assert_eq!(act(NommoMsg::EqValue(2), &mixer, &config).unwrap(), vec![]);
+        assert_eq!(
+            act(NommoMsg::EqValue(4), &mixer, &config).unwrap(),
+            vec![Call::Equalizer(EqCurve {
+                bass: 3.0,
+                treble: -1.5
+            })]
+        );
+        // falls back to `eq_value`, which has no preset for it
+        assert_eq!(act(NommoMsg::EqValue(9), &mixer, &config).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn maps_bound_buttons_only() {
+        let mut config = Config::default();
+        config.mappings.buttons = vec![ButtonBinding {
+            button: 2,
+            action: Action::VolumeDown,
+        }];
+        let mixer = mixer(50.0);
+        assert_eq!(
+            act(NommoMsg::Button(2), &mixer, &config).unwrap(),
+            vec![Call::Volume(stereo(at(45.0)))]
+        );
+        assert_eq!(act(NommoMsg::Button(3), &mixer, &config).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn runs_commands_with_the_value() {
+        let path = std::env::temp_dir().join(format!("nommo-value-{}", std::process::id()));
+        let mut config = Config::default();
+        config.mappings.buttons = vec![ButtonBinding {
+            button: 7,
+            action: Action::Command(format!(
+                "printf %s \"$NOMMO_VALUE\" > {0}.tmp && mv {0}.tmp {0}",
+                path.display()
+            )),
+        }];
+        let calls = act(NommoMsg::Button(7), &mixer(50.0), &config).unwrap();
+        assert!(calls.is_empty());
+        // the command runs in the background
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !path.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let value = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(value.unwrap(), "7");
+    }
+
+    #[test]
+    fn failed_media_commands_are_action_errors() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let mut config = Config::default();
+        config.mappings.vol_up = Action::Media(MediaCommand::PlayPause);
+        config.media.player = Some(String::from("no-such-player"));
+        let mixer = mixer(50.0);
+        let settings = Settings::new(&config, false, None);
+        let mut outputs = outputs(&mixer);
+        outputs.media = MediaClient::on(bus.session());
+        let action = settings.mappings.action(&NommoMsg::VolUp);
+        let error = dispatch(&NommoMsg::VolUp, action, STEP, &mut outputs, &settings).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Action error: No media player named no-such-player"
+        );
+        assert_eq!(error.policy(), Policy::Skip);
+        assert!(mixer.take_calls().is_empty());
+    }
+
+    #[test]
+    fn toggle_balance_switches_modes_without_touching_the_sink() {
+        let mixer = mixer(50.0);
+        let settings = Settings::new(&Config::default(), false, None);
+        let mut outputs = outputs(&mixer);
+        for active in [true, false] {
+            dispatch(
+                &NommoMsg::Button(1),
+                &Action::ToggleBalance,
+                STEP,
+                &mut outputs,
+                &settings,
+            )
+            .unwrap();
+            assert_eq!(outputs.balance.active, active);
+        }
+        assert!(mixer.take_calls().is_empty());
+    }
+
+    #[test]
+    fn volume_changes_keep_the_configured_balance() {
+        for channels in [2, 6] {
+            let mut config = Config::default();
+            config.volume.balance = -40.0;
+            let mixer = RecordingMixer::new(at(50.0), channels);
+            for action in [Action::VolumeUp, Action::VolumeDown] {
+                let calls = run(action, &mixer, &config);
+                let volumes = mixer.volumes();
+                assert_eq!(calls, vec![Call::Volume(volumes)]);
+                assert_eq!(volumes.len(), channels);
+                assert_near(balance(&mixer, &volumes), -0.4);
+            }
+            assert_eq!(mixer.volumes().max(), at(50.0));
+        }
+    }
+
+    #[test]
+    fn balance_mode_shifts_the_balance_up_to_the_side() {
+        for channels in [2, 6] {
+            let mut config = Config::default();
+            config.volume.balance = 90.0;
+            let settings = Settings::new(&config, false, None);
+            let mixer = RecordingMixer::new(at(50.0), channels);
+            let mut outputs = outputs(&mixer);
+            outputs.balance.active = true;
+            let mut turn = |action: Action| {
+                dispatch(&NommoMsg::Noop, &action, STEP, &mut outputs, &settings).unwrap();
+                balance(&mixer, &mixer.volumes())
+            };
+            assert_near(turn(Action::VolumeUp), 0.95);
+            assert_near(turn(Action::VolumeUp), 1.0);
+            // clamped at the right, so turning back moves at once
+            assert_near(turn(Action::VolumeDown), 0.95);
+            assert_eq!(mixer.volumes().max(), at(50.0));
+        }
+    }
+
+    #[test]
+    fn balance_mode_stops_at_the_left() {
+        let mut config = Config::default();
+        config.volume.balance = -95.0;
+        let settings = Settings::new(&config, false, None);
+        let mixer = RecordingMixer::new(at(50.0), 2);
+        let mut outputs = outputs(&mixer);
+        outputs.balance.active = true;
+        for _ in 0..3 {
+            let action = Action::VolumeDown;
+            dispatch(&NommoMsg::Noop, &action, STEP, &mut outputs, &settings).unwrap();
+        }
+        assert_near(balance(&mixer, &mixer.volumes()), -1.0);
+        dispatch(
+            &NommoMsg::Noop,
+            &Action::VolumeUp,
+            STEP,
+            &mut outputs,
+            &settings,
+        )
+        .unwrap();
+        assert_near(balance(&mixer, &mixer.volumes()), -0.95);
+    }
+
+    #[test]
+    fn replayed_fast_turns_take_larger_steps() {
+        let path = std::env::temp_dir().join(format!("nommo-accel-{}.txt", std::process::id()));
+        std::fs::write(&path, "01 e9\n+50 01 e9\n+200 01 e9\n+500 01 e9\n").unwrap();
+        let source = ReplaySource::from_file(&path);
+        let _ = std::fs::remove_file(&path);
+
+        let mut config = Config::default();
+        config.acceleration.enabled = true;
+        let mixer = mixer(50.0);
+        replay_to(source.unwrap(), &mut outputs(&mixer), &config).unwrap();
+        assert_eq!(
+            mixer.take_calls(),
+            vec![
+                Call::Volume(stereo(at(51.0))),
+                Call::Volume(stereo(at(61.0))),
+                Call::Volume(stereo(at(66.0))),
+                Call::Volume(stereo(at(67.0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_rapid_turns_are_applied_at_once() {
+        let mut config = Config::default();
+        config.volume.coalesce_ms = 200;
+        let wait = ReplayEntry::Wait(Duration::from_millis(10));
+        let mut entries = vec![report(&[1, 233])];
+        for _ in 0..3 {
+            entries.extend([wait.clone(), report(&[1, 233])]);
+        }
+        let source = ReplaySource::new(entries);
+        // the replay's clock now lags behind, which must not make the turns look overdue
+        thread::sleep(Duration::from_millis(250));
+        let mixer = mixer(50.0);
+        replay_to(source, &mut outputs(&mixer), &config).unwrap();
+        assert_eq!(mixer.take_calls(), vec![Call::Volume(stereo(at(70.0)))]);
+    }
+
+    #[test]
+    fn replayed_turns_after_the_window_start_another_burst() {
+        let mut config = Config::default();
+        config.volume.coalesce_ms = 1000;
+        let entries = vec![
+            report(&[1, 233]),
+            report(&[1, 233]),
+            ReplayEntry::Wait(Duration::from_millis(1500)),
+            report(&[1, 234]),
+            report(&[1, 234]),
+        ];
+        let calls = replay(entries, &mixer(50.0), &config).unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                Call::Volume(stereo(at(60.0))),
+                Call::Volume(stereo(at(50.0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn shutdown_applies_the_pending_turns() {
+        let mut config = Config::default();
+        config.volume.coalesce_ms = 60_000;
+        let mut control = control();
+        let source = ShuttingDown {
+            reports: vec![vec![1, 233], vec![1, 233]],
+            shutdown: control.shutdown.clone(),
+        };
+        let rules = profile::DEFAULT.rules().unwrap();
+        let mixer = mixer(50.0);
+        let mut settings = Settings::new(&config, false, None);
+        let result = handle_device(
+            Box::new(source),
+            &rules,
+            &mut outputs(&mixer),
+            &mut settings,
+            &mut control,
+        );
+        assert!(result.is_ok());
+        assert_eq!(mixer.take_calls(), vec![Call::Volume(stereo(at(60.0)))]);
+    }
+
+    #[test]
+    fn a_turn_after_an_outside_change_starts_from_the_new_volume() {
+        let mixer = mixer(50.0);
+        let source = ReplaySource::new(vec![
+            report(&[1, 233]),
+            ReplayEntry::Sink(OutsideChange::Volume(25.0)),
+            report(&[1, 233]),
+        ])
+        .with_mixer(mixer.memory());
+        replay_to(source, &mut outputs(&mixer), &each_turn()).unwrap();
+        assert_eq!(
+            mixer.take_calls(),
+            vec![
+                Call::Volume(stereo(at(55.0))),
+                Call::Volume(stereo(at(30.0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn outside_changes_to_the_default_sink_are_published() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let mixer = mixer(50.0);
+        let service = bus.service(&Config::default(), &mixer.memory());
+        let driver = bus.driver();
+        let mut signals = driver.receive_signal("SinkChanged").unwrap();
+        let mut next_signal =
+            || -> (String, f64, bool) { signals.next().unwrap().body().deserialize().unwrap() };
+        let mut outputs = Outputs::new(Box::new(mixer.connect()), Some(service), None);
+        let settings = Settings::new(&Config::default(), false, None);
+
+        publish_changes(&mut outputs, &settings);
+        assert_eq!(next_signal(), (String::from("memory"), 50.0, false));
+        publish_changes(&mut outputs, &settings);
+        mixer.change(OutsideChange::Volume(25.0));
+        publish_changes(&mut outputs, &settings);
+        assert_eq!(next_signal(), (String::from("memory"), 25.0, false));
+
+        // not while a configured or overriding sink is controlled instead
+        let mut config = Config::default();
+        config.volume.sink = Some(String::from("memory"));
+        mixer.change(OutsideChange::Mute(true));
+        publish_changes(&mut outputs, &Settings::new(&config, false, None));
+        driver
+            .call::<_, _, ()>("SetTargetSink", &("memory",))
+            .unwrap();
+        publish_changes(&mut outputs, &settings);
+        driver.call::<_, _, ()>("SetTargetSink", &("",)).unwrap();
+        publish_changes(&mut outputs, &settings);
+        assert_eq!(next_signal(), (String::from("memory"), 25.0, true));
+    }
+
+    #[test]
+    fn reloaded_settings_apply_to_the_next_report() {
+        let mut config = each_turn();
+        config.volume.sink = Some(String::from("headphones"));
+        let mut reloaded = each_turn();
+        reloaded.volume.step = 25.0;
+        let mut control = Control {
+            watcher: ConfigWatcher::scripted(vec![Ok(reloaded), Err(String::from("bad edit"))]),
+            shutdown: Shutdown::default(),
+        };
+        let source = Reloading {
+            steps: vec![
+                Step::Report(vec![1, 233]),
+                Step::Reload,
+                Step::Report(vec![1, 233]),
+                Step::Reload,
+                Step::Report(vec![1, 233]),
+            ],
+            watcher: control.watcher.clone(),
+        };
+        let rules = profile::DEFAULT.rules().unwrap();
+        let mixer = mixer(50.0);
+        let mut settings = Settings::new(&config, false, None);
+        handle_device(
+            Box::new(source),
+            &rules,
+            &mut outputs(&mixer),
+            &mut settings,
+            &mut control,
+        )
+        .unwrap();
+        // the configured sink does not exist, so only the turns after the reload change the
+        // default sink, by the reloaded step; the invalid edit keeps them going
+        assert_eq!(
+            mixer.take_calls(),
+            vec![
+                Call::Volume(stereo(at(75.0))),
+                Call::Volume(stereo(at(100.0))),
+            ]
+        );
+        assert_eq!(settings.step, 0.25);
+        assert_eq!(settings.sink, None);
+    }
+
+    #[test]
+    fn sigterm_applies_the_pending_turns_and_ends_the_device() {
+        let mut config = Config::default();
+        config.volume.coalesce_ms = 60_000;
+        // the only test to register the signal handlers, so a single signal only sets the flag
+        let shutdown = Shutdown::on_signals().unwrap();
+        let mut control = Control {
+            watcher: ConfigWatcher::idle(),
+            shutdown: shutdown.clone(),
+        };
+        let source = Terminated {
+            reports: vec![vec![1, 233], vec![1, 233]],
+            raised: false,
+        };
+        let rules = profile::DEFAULT.rules().unwrap();
+        let mixer = mixer(50.0);
+        let mut settings = Settings::new(&config, false, None);
+        assert!(!shutdown.requested());
+        let result = handle_device(
+            Box::new(source),
+            &rules,
+            &mut outputs(&mixer),
+            &mut settings,
+            &mut control,
+        );
+        assert!(shutdown.requested());
+        assert!(result.is_ok());
+        assert_eq!(mixer.take_calls(), vec![Call::Volume(stereo(at(60.0)))]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+
+use hidapi::HidError;
+
+use crate::audio::AudioError;
+use crate::rules::DecodeError;
+
+/// Anything that can go wrong while driving a device.
+#[derive(Debug)]
+pub enum Error {
+    Hid(HidError),
+    Audio(AudioError),
+    Decode(DecodeError),
+    Config(String),
+    Replay(String),
+    /// A bound action other than a volume change failed.
+    Action(String),
+}
+
+/// What the event loop does about an error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Reconnect to whatever failed and try again.
+    Retry,
+    /// Drop the report that caused it and carry on.
+    Skip,
+    /// Give up; `main` exits with the error's exit code.
+    Exit,
+}
+
+impl Error {
+    pub fn policy(&self) -> Policy {
+        match self {
+            Error::Audio(error) if !error.is_disconnected() => Policy::Skip,
+            Error::Hid(_) | Error::Audio(_) => Policy::Retry,
+            Error::Decode(_) | Error::Action(_) => Policy::Skip,
+            Error::Config(_) | Error::Replay(_) => Policy::Exit,
+        }
+    }
+
+    /// Exit status for `main`, following the BSD `sysexits.h` codes.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // EX_UNAVAILABLE
+            Error::Hid(_) | Error::Audio(_) => 69,
+            // EX_DATAERR
+            Error::Decode(_) => 65,
+            // EX_CONFIG
+            Error::Config(_) => 78,
+            // EX_NOINPUT
+            Error::Replay(_) => 66,
+            // EX_SOFTWARE
+            Error::Action(_) => 70,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Hid(error) => write!(f, "Device error: {}", error),
+            Error::Audio(error) => write!(f, "Audio error: {}", error),
+            Error::Decode(error) => write!(f, "Decode error: {}", error),
+            Error::Config(error) => write!(f, "Config error: {}", error),
+            Error::Replay(error) => write!(f, "Replay error: {}", error),
+            Error::Action(error) => write!(f, "Action error: {}", error),
+        }
+    }
+}
+
+impl From<HidError> for Error {
+    fn from(error: HidError) -> Self {
+        Error::Hid(error)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(error: DecodeError) -> Self {
+        Error::Decode(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_error_has_a_policy_and_exit_code() {
+        let errors = vec![
+            (
+                Error::Hid(HidError::HidApiError {
+                    message: String::from("gone"),
+                }),
+                Policy::Retry,
+                69,
+            ),
+            (
+                Error::Audio(AudioError::Disconnected(String::from("gone"))),
+                Policy::Retry,
+                69,
+            ),
+            (
+                Error::Audio(AudioError::NotFound(String::from("No such sink: hdmi"))),
+                Policy::Skip,
+                69,
+            ),
+            (
+                Error::Audio(AudioError::Unsupported(String::from("No equalizer"))),
+                Policy::Skip,
+                69,
+            ),
+            (Error::Decode(DecodeError::Empty), Policy::Skip, 65),
+            (Error::Config(String::from("bad")), Policy::Exit, 78),
+            (Error::Replay(String::from("missing")), Policy::Exit, 66),
+            (Error::Action(String::from("failed")), Policy::Skip, 70),
+        ];
+        for (error, policy, code) in errors {
+            assert_eq!(error.policy(), policy, "{}", error);
+            assert_eq!(error.exit_code(), code, "{}", error);
+        }
+    }
+}
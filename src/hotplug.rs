@@ -0,0 +1,55 @@
+use std::thread;
+use std::time::Duration;
+
+/// Blocks until a device may have been plugged in.
+pub trait HotplugMonitor {
+    /// Waits for a device add event or for `timeout` to pass, whichever comes first, and returns
+    /// whether an event arrived.
+    fn wait(&mut self, timeout: Duration) -> bool;
+}
+
+/// Fallback monitor that just sleeps, leaving it to the next scan to find new devices.
+pub struct PollMonitor;
+
+impl HotplugMonitor for PollMonitor {
+    fn wait(&mut self, timeout: Duration) -> bool {
+        thread::sleep(timeout);
+        false
+    }
+}
+
+/// Wakes up as soon as udev announces a new hidraw device.
+#[cfg(feature = "udev")]
+pub struct UdevMonitor {
+    socket: udev::MonitorSocket,
+}
+
+#[cfg(feature = "udev")]
+impl UdevMonitor {
+    pub fn new() -> std::io::Result<Self> {
+        let socket = udev::MonitorBuilder::new()?
+            .match_subsystem("hidraw")?
+            .listen()?;
+        Ok(UdevMonitor { socket })
+    }
+}
+
+#[cfg(feature = "udev")]
+impl HotplugMonitor for UdevMonitor {
+    fn wait(&mut self, timeout: Duration) -> bool {
+        use std::os::unix::io::AsRawFd;
+
+        let mut fds = [libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout_ms) };
+        if ready > 0 {
+            // drain the socket; whatever arrived, the caller scans for devices again
+            self.socket.iter().for_each(drop);
+        }
+        ready > 0
+    }
+}
@@ -1,118 +1,132 @@
-use std::convert::TryFrom;
+use std::process;
 
-use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
-use pulsectl::controllers::DeviceControl;
-use pulsectl::controllers::SinkController;
+use clap::Parser;
 
-const NOMMO_VENDOR_ID: u16 = 0x1532;
-const NOMMO_PRODUCT_ID: u16 = 0x0517;
-const VOL_DELTA: f64 = 0.05;
+use audio::AudioError;
+use cli::Args;
+use config::Config;
+use device::HidBus;
+use driver::{Control, Outputs, Settings};
+use error::Error;
+#[cfg(feature = "udev")]
+use hotplug::UdevMonitor;
+use hotplug::{HotplugMonitor, PollMonitor};
+use notify::Notifier;
+use profile::Profile;
+use reload::ConfigWatcher;
+use report::ReplaySource;
+use service::Service;
+use shutdown::Shutdown;
+use supervisor::HandlerContext;
+
+mod accel;
+mod audio;
+mod cli;
+mod config;
+mod curve;
+mod device;
+mod driver;
+mod error;
+mod hotplug;
+mod media;
+mod notify;
+mod profile;
+mod reload;
+mod report;
+mod rules;
+mod service;
+mod session;
+mod shutdown;
+mod supervisor;
 
 #[derive(Debug, PartialEq)]
 enum NommoMsg {
     VolUp,
     VolDown,
     EqValue(u8),
+    Button(u8),
     Noop,
 }
 
-impl TryFrom<&[u8; 16]> for NommoMsg {
-    type Error = String;
-
-    fn try_from(
-        other: &[u8; 16],
-    ) -> std::result::Result<Self, <Self as std::convert::TryFrom<&[u8; 16]>>::Error> {
-        match other {
-            [1, 233, ..] => Ok(Self::VolUp),
-            [1, 234, ..] => Ok(Self::VolDown),
-            [5, 15, _, v, ..] => Ok(Self::EqValue(*v)),
-            _ => Ok(Self::Noop),
-        }
+fn hotplug_monitor() -> Box<dyn HotplugMonitor> {
+    #[cfg(feature = "udev")]
+    match UdevMonitor::new() {
+        Ok(monitor) => return Box::new(monitor),
+        Err(error) => eprintln!("Cannot listen for udev events, polling instead: {}", error),
     }
+    Box::new(PollMonitor)
 }
 
-fn volume_from_percent(delta: f64) -> Volume {
-    let vol_raw = (delta * 100.0) * (f64::from(VOLUME_NORM.0) / 100.0);
-    Volume::from(Volume(vol_raw as u32))
-}
-
-fn set_volume(volumes: &ChannelVolumes, sink_controller: &mut SinkController, sink_index: u32) {
-    let op = sink_controller
-        .handler
-        .introspect
-        .set_sink_volume_by_index(sink_index, &volumes, None);
-    sink_controller
-        .handler
-        .wait_for_operation(op)
-        .expect("Error setting volume");
-}
-
-fn set_mute(mute: bool, sink_controller: &mut SinkController, sink_index: u32) {
-    let op = sink_controller
-        .handler
-        .introspect
-        .set_sink_mute_by_index(sink_index, mute, None);
-
-    sink_controller
-        .handler
-        .wait_for_operation(op)
-        .expect("Error setting volume");
+fn main() {
+    if let Err(error) = run(Args::parse()) {
+        eprintln!("{}", error);
+        process::exit(error.exit_code());
+    }
 }
 
-fn handle_device(dev_handle: hidapi::HidDevice) {
-    // get Pulse Audio default device
-    let mut sink_controller = SinkController::create();
-
-    let mut buff = [0 as u8; 16];
-    loop {
-        let default_sink = sink_controller
-            .get_default_device()
-            .expect("Cannot get PulseAudio default sink");
-        let mut current_volume = default_sink.clone().volume;
+fn run(args: Args) -> Result<(), Error> {
+    let config = Config::load(&args).map_err(Error::Config)?;
 
-        dev_handle.read(&mut buff).expect("Cannot read from device");
-        let msg = NommoMsg::try_from(&buff).expect("Cannot convert data");
-        match msg {
-            NommoMsg::VolUp => {
-                let volumes = current_volume
-                    .inc_clamp(volume_from_percent(VOL_DELTA), volume_from_percent(1.0))
-                    .expect("Cannot set new ChannelVolumes");
-                set_volume(volumes, &mut sink_controller, default_sink.index);
+    if args.print_config {
+        print!("{}", config.to_toml().map_err(Error::Config)?);
+        return Ok(());
+    }
 
-                // if muted, unmute
-                if default_sink.mute {
-                    set_mute(false, &mut sink_controller, default_sink.index);
-                }
-            }
-            NommoMsg::VolDown => {
-                let volumes = current_volume
-                    .decrease(volume_from_percent(VOL_DELTA))
-                    .expect("Cannot set new ChannelVolumes");
-                set_volume(volumes, &mut sink_controller, default_sink.index);
+    let shutdown = Shutdown::on_signals().unwrap_or_else(|error| {
+        eprintln!("Cannot listen for SIGTERM: {}", error);
+        Shutdown::default()
+    });
+    let watcher = ConfigWatcher::start(&args);
+    let mixer = audio::memory_mixer(&config.backend);
+    let connect = audio::connector(mixer.clone());
+    let notifier = if config.notifications.enabled {
+        Some(Notifier::start(&config.notifications))
+    } else {
+        None
+    };
+    let service = if config.dbus.enabled {
+        Service::start(
+            &config,
+            args.verbose,
+            &watcher,
+            notifier.clone(),
+            connect.clone(),
+        )
+        .map_err(|error| eprintln!("D-Bus service disabled: {}", error))
+        .ok()
+    } else {
+        None
+    };
 
-                // if volume at 0%, mute
-                if default_sink.volume == volume_from_percent(0.0) && !default_sink.mute {
-                    set_mute(true, &mut sink_controller, default_sink.index);
-                }
-            }
-            _ => {}
+    let path = match &args.replay {
+        Some(path) => path,
+        None => {
+            let mut bus = HidBus::new()?;
+            let mut monitor = hotplug_monitor();
+            let control = Control { watcher, shutdown };
+            let context =
+                HandlerContext::new(&config, args.verbose, control, service, notifier, connect);
+            return supervisor::supervise(&context, &mut bus, monitor.as_mut());
         }
-    }
-}
+    };
 
-fn main() {
-    match hidapi::HidApi::new() {
-        Ok(api) => {
-            let device = api.open(NOMMO_VENDOR_ID, NOMMO_PRODUCT_ID);
-            match device {
-                Ok(handle) => handle_device(handle),
-                Err(error) => {
-                    eprintln!("Device error: {}", error);
-                }
-            }
-        }
-        Err(error) => {
-            eprintln!("Error: {}", error);
-        }
+    let mut source = ReplaySource::from_file(path).map_err(Error::Replay)?;
+    if config.backend.name == "memory" {
+        source = source.with_mixer(mixer);
     }
+    let audio =
+        connect(&config.backend).map_err(|error| Error::Audio(AudioError::Disconnected(error)))?;
+    let profile = match (config.device.vid, config.device.pid) {
+        (Some(vid), Some(pid)) => Profile::by_ids(vid, pid).unwrap_or(profile::DEFAULT),
+        _ => profile::DEFAULT,
+    };
+    let rules = profile.rules().map_err(Error::Config)?;
+    let mut settings = Settings::new(&config, args.verbose, None);
+    driver::run_replay(
+        &source,
+        &rules,
+        &mut Outputs::new(audio, service, notifier),
+        &mut settings,
+        &mut Control { watcher, shutdown },
+    )
 }
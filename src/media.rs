@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use zbus::blocking::fdo::DBusProxy;
+use zbus::blocking::{Connection, Proxy};
+
+use crate::session::SessionBus;
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Player methods an action can call.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MediaCommand {
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+}
+
+impl MediaCommand {
+    fn method(self) -> &'static str {
+        match self {
+            MediaCommand::PlayPause => "PlayPause",
+            MediaCommand::Play => "Play",
+            MediaCommand::Pause => "Pause",
+            MediaCommand::Stop => "Stop",
+            MediaCommand::Next => "Next",
+            MediaCommand::Previous => "Previous",
+        }
+    }
+}
+
+/// Sends commands to MPRIS media players on the session bus.
+#[derive(Default)]
+pub struct MediaClient {
+    bus: SessionBus,
+}
+
+impl MediaClient {
+    /// Sends `command` to the player named `player`, e.g. `spotify`, or to the one most
+    /// likely in use: playing before paused before stopped.
+    pub fn send(&mut self, command: MediaCommand, player: Option<&str>) -> Result<(), String> {
+        self.bus
+            .call(|connection| send(connection, command, player))
+    }
+
+    /// A client of the players on `bus` instead of the session bus.
+    #[cfg(test)]
+    pub fn on(bus: SessionBus) -> Self {
+        MediaClient { bus }
+    }
+}
+
+fn send(
+    connection: &Connection,
+    command: MediaCommand,
+    player: Option<&str>,
+) -> Result<(), String> {
+    let players = list_players(connection)?;
+    let name = match player {
+        Some(player) => players
+            .into_iter()
+            .find(|name| is_instance_of(name, player))
+            .ok_or_else(|| format!("No media player named {}", player))?,
+        None => most_active(players, |name| playback_status(connection, name))
+            .ok_or("No media player running")?,
+    };
+    let proxy = player_proxy(connection, &name)?;
+    proxy
+        .call_method(command.method(), &())
+        .map(|_| ())
+        .map_err(|e| format!("Cannot send {} to {}: {}", command.method(), name, e))
+}
+
+fn list_players(connection: &Connection) -> Result<Vec<String>, String> {
+    let names = DBusProxy::new(connection)
+        .map_err(|e| e.to_string())
+        .and_then(|proxy| proxy.list_names().map_err(|e| e.to_string()))
+        .map_err(|e| format!("Cannot list bus names: {}", e))?;
+    Ok(names
+        .into_iter()
+        .map(|name| name.to_string())
+        .filter(|name| name.starts_with(MPRIS_PREFIX))
+        .collect())
+}
+
+/// Matches `org.mpris.MediaPlayer2.vlc` as well as instances like `...vlc.instance1234`.
+fn is_instance_of(name: &str, player: &str) -> bool {
+    let short = &name[MPRIS_PREFIX.len()..];
+    short == player || short.starts_with(&format!("{}.", player))
+}
+
+fn player_proxy<'a>(connection: &Connection, name: &'a str) -> Result<Proxy<'a>, String> {
+    Proxy::new(connection, name, MPRIS_PATH, PLAYER_INTERFACE)
+        .map_err(|e| format!("Cannot reach media player {}: {}", name, e))
+}
+
+/// The player's `PlaybackStatus`, or an empty string if it cannot be read.
+fn playback_status(connection: &Connection, name: &str) -> String {
+    player_proxy(connection, name)
+        .and_then(|proxy| {
+            proxy
+                .get_property::<String>("PlaybackStatus")
+                .map_err(|e| e.to_string())
+        })
+        .unwrap_or_default()
+}
+
+/// The player most likely in use: the first playing one, else the first paused one, else the
+/// first one.
+fn most_active(players: Vec<String>, status: impl Fn(&str) -> String) -> Option<String> {
+    players
+        .into_iter()
+        .min_by_key(|name| status_rank(&status(name)))
+}
+
+/// Orders players by how likely they are in use, lowest first.
+fn status_rank(status: &str) -> u8 {
+    match status {
+        "Playing" => 0,
+        "Paused" => 1,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::service::PrivateBus;
+
+    /// An MPRIS player recording the methods called on it.
+    struct StubPlayer {
+        status: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl StubPlayer {
+        fn play_pause(&self) {
+            self.calls.lock().unwrap().push("PlayPause");
+        }
+
+        fn next(&self) {
+            self.calls.lock().unwrap().push("Next");
+        }
+
+        fn previous(&self) {
+            self.calls.lock().unwrap().push("Previous");
+        }
+
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            self.status.to_string()
+        }
+    }
+
+    /// Runs a player named `org.mpris.MediaPlayer2.<name>` on `bus`, until the returned
+    /// connection is dropped.
+    fn player(
+        bus: &PrivateBus,
+        name: &str,
+        status: &'static str,
+    ) -> (Connection, Arc<Mutex<Vec<&'static str>>>) {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let stub = StubPlayer {
+            status,
+            calls: calls.clone(),
+        };
+        let name = format!("{}{}", MPRIS_PREFIX, name);
+        (bus.serve(&name, MPRIS_PATH, stub), calls)
+    }
+
+    #[test]
+    fn sends_commands_to_the_most_active_or_the_named_player() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let (_vlc, vlc_calls) = player(&bus, "vlc", "Paused");
+        let (_spotify, spotify_calls) = player(&bus, "spotify.instance7", "Playing");
+        let mut client = MediaClient::on(bus.session());
+        let commands = [
+            MediaCommand::PlayPause,
+            MediaCommand::Next,
+            MediaCommand::Previous,
+        ];
+        for command in commands {
+            client.send(command, None).unwrap();
+        }
+        for command in commands {
+            client.send(command, Some("vlc")).unwrap();
+        }
+        let methods = vec!["PlayPause", "Next", "Previous"];
+        assert_eq!(*spotify_calls.lock().unwrap(), methods);
+        assert_eq!(*vlc_calls.lock().unwrap(), methods);
+
+        assert_eq!(
+            client.send(MediaCommand::Next, Some("mpv")),
+            Err(String::from("No media player named mpv"))
+        );
+    }
+
+    #[test]
+    fn matches_players_and_their_instances() {
+        assert!(is_instance_of("org.mpris.MediaPlayer2.vlc", "vlc"));
+        assert!(is_instance_of(
+            "org.mpris.MediaPlayer2.vlc.instance1234",
+            "vlc"
+        ));
+        assert!(!is_instance_of("org.mpris.MediaPlayer2.vlcx", "vlc"));
+        assert!(!is_instance_of("org.mpris.MediaPlayer2.spotify", "vlc"));
+    }
+
+    #[test]
+    fn prefers_playing_then_paused_players() {
+        let statuses = [
+            ("a", "Stopped"),
+            ("b", "Paused"),
+            ("c", "Playing"),
+            ("d", "Playing"),
+            ("e", ""),
+        ];
+        let pick = |names: &[&str]| {
+            let players = names.iter().map(|name| name.to_string()).collect();
+            most_active(players, |name| {
+                let (_, status) = statuses.iter().find(|(n, _)| *n == name).unwrap();
+                status.to_string()
+            })
+        };
+        assert_eq!(pick(&["a", "b", "c", "d"]), Some(String::from("c")));
+        assert_eq!(pick(&["a", "b"]), Some(String::from("b")));
+        // a player whose status cannot be read counts as stopped
+        assert_eq!(pick(&["e", "a"]), Some(String::from("e")));
+        assert_eq!(pick(&[]), None);
+    }
+}
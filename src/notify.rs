@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libpulse_binding::volume::ChannelVolumes;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::Value;
+
+use crate::audio;
+use crate::config::NotificationsConfig;
+use crate::session::SessionBus;
+
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+const APP_NAME: &str = "nommo_vol_driver";
+
+/// What a volume notification shows.
+struct Update {
+    sink: String,
+    volume: u32,
+    muted: bool,
+}
+
+/// Shows volume changes as a desktop notification that replaces the previous one.
+///
+/// Changes arriving within `interval_ms` of the last notification are merged, so a fast spin
+/// shows its final volume once the interval is over. Clones share the notification.
+#[derive(Clone)]
+pub struct Notifier {
+    updates: Sender<Update>,
+}
+
+impl Notifier {
+    pub fn start(config: &NotificationsConfig) -> Self {
+        Self::start_on(SessionBus::default(), config)
+    }
+
+    fn start_on(bus: SessionBus, config: &NotificationsConfig) -> Self {
+        let (updates, received) = mpsc::channel();
+        let interval = Duration::from_millis(config.interval_ms);
+        let timeout = config.timeout_ms;
+        thread::spawn(move || {
+            let mut notifications = Notifications { bus, id: 0 };
+            show_updates(received, interval, |update| {
+                if let Err(error) = notifications.show(update, timeout) {
+                    eprintln!("Cannot show notification: {}", error);
+                }
+            })
+        });
+        Notifier { updates }
+    }
+
+    pub fn volume_changed(&self, sink: &str, volumes: &ChannelVolumes, muted: bool) {
+        let update = Update {
+            sink: sink.to_string(),
+            volume: audio::percent(volumes).round() as u32,
+            muted,
+        };
+        // the thread showing them lives as long as the process
+        let _ = self.updates.send(update);
+    }
+}
+
+/// Shows each update received, or only the latest of those arriving within `interval` of the
+/// last one shown.
+fn show_updates(received: Receiver<Update>, interval: Duration, mut show: impl FnMut(&Update)) {
+    let mut last_shown = None;
+    while let Ok(mut update) = received.recv() {
+        let until = last_shown.map_or_else(Instant::now, |shown| shown + interval);
+        while let Some(wait) = until.checked_duration_since(Instant::now()) {
+            match received.recv_timeout(wait) {
+                Ok(newer) => update = newer,
+                Err(_) => break,
+            }
+        }
+        show(&update);
+        last_shown = Some(Instant::now());
+    }
+}
+
+/// The notification server on the session bus.
+struct Notifications {
+    bus: SessionBus,
+    /// ID of the notification to replace, 0 before the first one.
+    id: u32,
+}
+
+impl Notifications {
+    fn show(&mut self, update: &Update, timeout: i32) -> Result<(), String> {
+        let replaces = self.id;
+        self.id = self
+            .bus
+            .call(|connection| notify(connection, update, replaces, timeout))?;
+        Ok(())
+    }
+}
+
+/// Shows `update`, replacing the notification with ID `replaces`, and returns the new one's.
+fn notify(
+    connection: &Connection,
+    update: &Update,
+    replaces: u32,
+    timeout: i32,
+) -> Result<u32, String> {
+    let proxy = Proxy::new(connection, NOTIFICATIONS, NOTIFICATIONS_PATH, NOTIFICATIONS)
+        .map_err(|e| format!("Cannot reach the notification server: {}", e))?;
+
+    let summary = if update.muted {
+        String::from("Muted")
+    } else {
+        format!("Volume {}%", update.volume)
+    };
+    let mut hints = HashMap::new();
+    // draws a bar, and lets servers supporting it update the notification in place
+    hints.insert("value", Value::from(update.volume as i32));
+    hints.insert("synchronous", Value::from("volume"));
+    hints.insert("x-canonical-private-synchronous", Value::from("volume"));
+    let actions: Vec<&str> = vec![];
+    proxy
+        .call(
+            "Notify",
+            &(
+                APP_NAME,
+                replaces,
+                icon(update),
+                summary,
+                &update.sink,
+                actions,
+                hints,
+                timeout,
+            ),
+        )
+        .map_err(|e| format!("Notify failed: {}", e))
+}
+
+fn icon(update: &Update) -> &'static str {
+    match update.volume {
+        _ if update.muted => "audio-volume-muted",
+        0..=33 => "audio-volume-low",
+        34..=66 => "audio-volume-medium",
+        _ => "audio-volume-high",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use libpulse_binding::volume::{Volume, VOLUME_NORM};
+    use zbus::zvariant::OwnedValue;
+
+    use super::*;
+    use crate::service::PrivateBus;
+
+    fn update(volume: u32, muted: bool) -> Update {
+        Update {
+            sink: String::from("speakers"),
+            volume,
+            muted,
+        }
+    }
+
+    /// The arguments of a `Notify` call.
+    #[derive(Debug)]
+    struct Shown {
+        replaces_id: u32,
+        icon: String,
+        summary: String,
+        body: String,
+        hints: HashMap<String, OwnedValue>,
+        timeout: i32,
+    }
+
+    /// A notification server handing out IDs from 41 and passing on what it is asked to show.
+    struct StubServer {
+        shown: Mutex<Sender<Shown>>,
+        last_id: Mutex<u32>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl StubServer {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: String,
+            replaces_id: u32,
+            icon: String,
+            summary: String,
+            body: String,
+            _actions: Vec<String>,
+            hints: HashMap<String, OwnedValue>,
+            timeout: i32,
+        ) -> u32 {
+            let shown = Shown {
+                replaces_id,
+                icon,
+                summary,
+                body,
+                hints,
+                timeout,
+            };
+            let _ = self.shown.lock().unwrap().send(shown);
+            let mut id = self.last_id.lock().unwrap();
+            *id += 1;
+            *id
+        }
+    }
+
+    fn stereo(percent: u32) -> ChannelVolumes {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set(2, Volume(percent * VOLUME_NORM.0 / 100));
+        volumes
+    }
+
+    #[test]
+    fn merges_updates_within_the_interval() {
+        let interval = Duration::from_millis(100);
+        let (updates, received) = mpsc::channel();
+        let (shown, shows) = mpsc::channel();
+        thread::spawn(move || {
+            show_updates(received, interval, |update| {
+                let _ = shown.send((update.volume, Instant::now()));
+            })
+        });
+
+        updates.send(update(10, false)).unwrap();
+        let (first, first_at) = shows.recv().unwrap();
+        updates.send(update(20, false)).unwrap();
+        updates.send(update(30, false)).unwrap();
+        let (second, second_at) = shows.recv().unwrap();
+        assert_eq!((first, second), (10, 30));
+        assert!(second_at - first_at >= interval);
+
+        drop(updates);
+        assert!(shows.recv().is_err());
+    }
+
+    #[test]
+    fn shows_each_change_replacing_the_last_notification() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let (shown, received) = mpsc::channel();
+        let server = StubServer {
+            shown: Mutex::new(shown),
+            last_id: Mutex::new(40),
+        };
+        let _server = bus.serve(NOTIFICATIONS, NOTIFICATIONS_PATH, server);
+        let config = NotificationsConfig {
+            enabled: true,
+            interval_ms: 0,
+            timeout_ms: 1500,
+        };
+        let notifier = Notifier::start_on(bus.session(), &config);
+        let wait = Duration::from_secs(5);
+
+        notifier.volume_changed("speakers", &stereo(40), false);
+        let first = received.recv_timeout(wait).unwrap();
+        assert_eq!(first.replaces_id, 0);
+        assert_eq!(first.icon, "audio-volume-medium");
+        assert_eq!(first.summary, "Volume 40%");
+        assert_eq!(first.body, "speakers");
+        assert_eq!(first.timeout, 1500);
+        assert_eq!(*first.hints["value"], Value::from(40));
+        assert_eq!(*first.hints["synchronous"], Value::from("volume"));
+        assert_eq!(
+            *first.hints["x-canonical-private-synchronous"],
+            Value::from("volume")
+        );
+
+        notifier.volume_changed("speakers", &stereo(40), true);
+        let second = received.recv_timeout(wait).unwrap();
+        assert_eq!(second.replaces_id, 41);
+        assert_eq!(second.icon, "audio-volume-muted");
+        assert_eq!(second.summary, "Muted");
+
+        notifier.volume_changed("speakers", &stereo(50), false);
+        assert_eq!(received.recv_timeout(wait).unwrap().replaces_id, 42);
+    }
+
+    #[test]
+    fn picks_an_icon_for_the_volume() {
+        assert_eq!(icon(&update(0, false)), "audio-volume-low");
+        assert_eq!(icon(&update(33, false)), "audio-volume-low");
+        assert_eq!(icon(&update(34, false)), "audio-volume-medium");
+        assert_eq!(icon(&update(66, false)), "audio-volume-medium");
+        assert_eq!(icon(&update(67, false)), "audio-volume-high");
+        assert_eq!(icon(&update(150, false)), "audio-volume-high");
+        assert_eq!(icon(&update(80, true)), "audio-volume-muted");
+    }
+}
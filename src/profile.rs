@@ -0,0 +1,174 @@
+use crate::config::DeviceConfig;
+use crate::device::Interface;
+use crate::rules::{self, Rule};
+
+/// One supported model: how to find its control interface and how to decode its reports.
+///
+/// Supporting another model only takes a new entry in `PROFILES` and a rule file under `rules/`
+/// with samples of the reports captured from it; every rule needs at least one.
+#[derive(Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    pub vid: u16,
+    pub pid: u16,
+    /// HID usage page of the interface sending the knob reports, if the model has several.
+    pub usage_page: Option<u16>,
+    /// USB interface number of that interface, where the usage page is not enough.
+    pub interface: Option<i32>,
+    /// Built-in decoding rules, in the format of `[[rule]]` tables in the config.
+    pub rules: &'static str,
+}
+
+/// HID usage page of consumer controls, such as volume keys and knobs.
+const CONSUMER: u16 = 0x0c;
+
+/// Every model the driver knows, in lookup order.
+pub const PROFILES: &[Profile] = &[
+    // also sold as the Nommo Chroma
+    Profile {
+        name: "Razer Nommo 2",
+        vid: 0x1532,
+        pid: 0x0517,
+        usage_page: Some(CONSUMER),
+        interface: None,
+        rules: include_str!("../rules/nommo2.toml"),
+    },
+];
+
+/// Used for devices picked by explicit IDs that have no profile of their own.
+pub const DEFAULT: &Profile = &PROFILES[0];
+
+impl Profile {
+    /// Whether `interface` is the model's control interface; an interface whose usage page
+    /// is unknown is taken for it.
+    fn matches(&self, interface: &Interface) -> bool {
+        interface.vid == self.vid
+            && interface.pid == self.pid
+            && self
+                .usage_page
+                .is_none_or(|page| interface.usage_page == page || interface.usage_page == 0)
+            && self
+                .interface
+                .is_none_or(|number| interface.number == number)
+    }
+
+    pub fn rules(&self) -> Result<Vec<Rule>, String> {
+        rules::parse(self.rules).map_err(|e| format!("Built-in rules for {}: {}", self.name, e))
+    }
+
+    /// The profile registered for a model, if any.
+    pub fn by_ids(vid: u16, pid: u16) -> Option<&'static Profile> {
+        PROFILES
+            .iter()
+            .find(|profile| profile.vid == vid && profile.pid == pid)
+    }
+
+    /// The profile to drive an enumerated HID interface with, or `None` to leave it alone.
+    ///
+    /// Without IDs in the config every known model is picked up. With them, only that model is,
+    /// falling back to `DEFAULT` if it has no profile.
+    pub fn select(interface: &Interface, device: &DeviceConfig) -> Option<&'static Profile> {
+        if device.vid.is_some_and(|vid| vid != interface.vid)
+            || device.pid.is_some_and(|pid| pid != interface.pid)
+        {
+            return None;
+        }
+        if let Some(profile) = PROFILES.iter().find(|profile| profile.matches(interface)) {
+            return Some(profile);
+        }
+        let known = Self::by_ids(interface.vid, interface.pid).is_some();
+        if !known && device.vid.is_some() && device.pid.is_some() {
+            Some(DEFAULT)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceId;
+
+    #[test]
+    fn built_in_rules_decode_their_samples() {
+        for profile in PROFILES {
+            let rules = profile.rules().unwrap_or_else(|error| panic!("{}", error));
+            for (index, rule) in rules.iter().enumerate() {
+                assert!(
+                    !rule.samples.is_empty(),
+                    "{}: rule {} has no captured samples",
+                    profile.name,
+                    index + 1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finds_every_profile_by_its_ids() {
+        for profile in PROFILES {
+            let found = Profile::by_ids(profile.vid, profile.pid).unwrap();
+            assert_eq!(found.name, profile.name);
+        }
+        assert!(Profile::by_ids(0x1532, 0xffff).is_none());
+    }
+
+    fn interface(pid: u16, usage_page: u16, number: i32) -> Interface {
+        Interface {
+            id: DeviceId {
+                path: format!("/dev/hidraw{}", number),
+                serial: None,
+            },
+            vid: 0x1532,
+            pid,
+            usage_page,
+            number,
+        }
+    }
+
+    #[test]
+    fn drives_only_the_consumer_control_interface() {
+        let device = DeviceConfig::default();
+        for profile in PROFILES {
+            let knob = interface(profile.pid, CONSUMER, 2);
+            assert_eq!(Profile::select(&knob, &device).unwrap().name, profile.name);
+            let vendor = interface(profile.pid, 0xff00, 3);
+            assert!(
+                Profile::select(&vendor, &device).is_none(),
+                "{}",
+                profile.name
+            );
+        }
+    }
+
+    #[test]
+    fn takes_an_interface_of_unknown_usage_page_for_the_control_one() {
+        let unknown = interface(0x0517, 0, 0);
+        let selected = Profile::select(&unknown, &DeviceConfig::default());
+        assert_eq!(selected.unwrap().name, "Razer Nommo 2");
+    }
+
+    #[test]
+    fn picks_only_the_configured_model() {
+        let device = DeviceConfig {
+            pid: Some(0x0517),
+            ..DeviceConfig::default()
+        };
+        let nommo = interface(0x0517, CONSUMER, 2);
+        assert_eq!(
+            Profile::select(&nommo, &device).unwrap().name,
+            "Razer Nommo 2"
+        );
+        assert!(Profile::select(&interface(0x0518, CONSUMER, 2), &device).is_none());
+
+        // a model without a profile is decoded like the default one
+        let device = DeviceConfig {
+            vid: Some(0x1532),
+            pid: Some(0x0999),
+            ..DeviceConfig::default()
+        };
+        let other = interface(0x0999, CONSUMER, 2);
+        assert_eq!(Profile::select(&other, &device).unwrap().name, DEFAULT.name);
+    }
+}
@@ -0,0 +1,227 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+#[cfg(test)]
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use inotify::{Inotify, WatchMask};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use crate::cli::Args;
+use crate::config::Config;
+
+/// Watches the config file and SIGHUP, reloading the configuration on request.
+///
+/// Every clone notices each change once, so each device handler can hold its own.
+#[derive(Clone)]
+pub struct ConfigWatcher {
+    changes: Arc<AtomicUsize>,
+    seen: usize,
+    args: Args,
+    /// What the next reloads return instead of loading the config, in tests.
+    #[cfg(test)]
+    scripted: Arc<Mutex<VecDeque<Result<Config, String>>>>,
+}
+
+impl ConfigWatcher {
+    /// Starts watching; failing to set up either trigger is logged and otherwise ignored.
+    pub fn start(args: &Args) -> Self {
+        let changes = Arc::new(AtomicUsize::new(0));
+
+        if let Err(error) = watch_signal(changes.clone()) {
+            eprintln!("Cannot listen for SIGHUP: {}", error);
+        }
+        if let Some(path) = Config::path(args) {
+            if let Err(error) = watch_file(&path, changes.clone()) {
+                eprintln!("Cannot watch {}: {}", path.display(), error);
+            }
+        }
+
+        ConfigWatcher {
+            changes,
+            seen: 0,
+            args: args.clone(),
+            #[cfg(test)]
+            scripted: Arc::default(),
+        }
+    }
+
+    /// A watcher that never reloads, for driving the event loop in tests.
+    #[cfg(test)]
+    pub fn idle() -> Self {
+        Self::scripted(vec![])
+    }
+
+    /// A watcher whose reloads return `loads` in turn, each after a call to `reload`.
+    #[cfg(test)]
+    pub fn scripted(loads: Vec<Result<Config, String>>) -> Self {
+        use clap::Parser;
+
+        ConfigWatcher {
+            changes: Arc::default(),
+            seen: 0,
+            args: Args::parse_from(["nommo_vol_driver"]),
+            scripted: Arc::new(Mutex::new(loads.into())),
+        }
+    }
+
+    /// Signals a change, as SIGHUP does, and waits until a clone has reloaded.
+    #[cfg(test)]
+    pub fn reload(&self) {
+        let pending = || self.scripted.lock().unwrap().len();
+        let before = pending();
+        self.changes.fetch_add(1, Ordering::SeqCst);
+        while pending() == before {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Reloads the configuration if a change was signalled since the last call.
+    pub fn poll(&mut self) -> Option<Result<Config, String>> {
+        let changes = self.changes.load(Ordering::SeqCst);
+        if changes != self.seen {
+            self.seen = changes;
+            #[cfg(test)]
+            if let Some(load) = self.scripted.lock().unwrap().pop_front() {
+                return Some(load);
+            }
+            Some(Config::load(&self.args))
+        } else {
+            None
+        }
+    }
+}
+
+fn watch_signal(changes: Arc<AtomicUsize>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            changes.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    Ok(())
+}
+
+const WATCH_MASK: WatchMask = WatchMask::CLOSE_WRITE
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::CREATE);
+
+/// The nearest existing directory on the way to `path`, and the name in it that leads there.
+fn nearest_existing(path: &Path) -> (PathBuf, Option<OsString>) {
+    let mut target = path;
+    loop {
+        let dir = target
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if dir.is_dir() || dir == target {
+            return (dir.to_path_buf(), target.file_name().map(OsString::from));
+        }
+        target = dir;
+    }
+}
+
+/// Watches the file's directory rather than the file itself, so editors that save by
+/// renaming a temporary file over the original are noticed too. While the directory does not
+/// exist yet, its nearest existing ancestor is watched instead, moving down as the directories
+/// on the way are created.
+fn watch_file(path: &Path, changes: Arc<AtomicUsize>) -> io::Result<()> {
+    let path = path.to_path_buf();
+    let (mut dir, mut name) = nearest_existing(&path);
+    let mut inotify = Inotify::init()?;
+    let mut watch = inotify.watches().add(&dir, WATCH_MASK)?;
+
+    thread::spawn(move || {
+        let mut buffer = [0_u8; 4096];
+        loop {
+            let created = match inotify.read_events_blocking(&mut buffer) {
+                Ok(mut events) => events.any(|event| event.name.map(OsString::from) == name),
+                Err(error) => {
+                    eprintln!("Config watcher stopped: {}", error);
+                    return;
+                }
+            };
+            if !created {
+                continue;
+            }
+            if name.as_ref().is_some_and(|name| dir.join(name) == path) {
+                changes.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+
+            // a directory on the way appeared; the file may already be in it
+            let _ = inotify.watches().remove(watch.clone());
+            (dir, name) = nearest_existing(&path);
+            watch = match inotify.watches().add(&dir, WATCH_MASK) {
+                Ok(watch) => watch,
+                Err(error) => {
+                    eprintln!("Config watcher stopped: {}", error);
+                    return;
+                }
+            };
+            if path.exists() {
+                changes.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn finds_the_nearest_existing_directory() {
+        let root = std::env::temp_dir().join(format!("nommo-reload-{}", std::process::id()));
+        let config = root.join("a").join("b").join("config.toml");
+        fs::create_dir_all(&root).unwrap();
+
+        assert_eq!(
+            nearest_existing(&config),
+            (root.clone(), Some(OsString::from("a")))
+        );
+        fs::create_dir_all(config.parent().unwrap()).unwrap();
+        assert_eq!(
+            nearest_existing(&config),
+            (
+                root.join("a").join("b"),
+                Some(OsString::from("config.toml"))
+            )
+        );
+        assert_eq!(
+            nearest_existing(Path::new("config.toml")),
+            (PathBuf::from("."), Some(OsString::from("config.toml")))
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn notices_a_config_created_after_startup() {
+        let root = std::env::temp_dir().join(format!("nommo-watch-{}", std::process::id()));
+        let config = root.join("a").join("b").join("config.toml");
+        fs::create_dir_all(&root).unwrap();
+        let changes = Arc::new(AtomicUsize::new(0));
+        watch_file(&config, changes.clone()).unwrap();
+
+        fs::create_dir(root.join("a")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        fs::create_dir(root.join("a").join("b")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        fs::write(&config, "").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while changes.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(changes.load(Ordering::SeqCst) > 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
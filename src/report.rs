@@ -0,0 +1,299 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::num::ParseIntError;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hidapi::{HidDevice, HidError, HidResult};
+
+use crate::audio::{MemoryMixer, OutsideChange};
+
+/// Outcome of waiting for a report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Read {
+    /// A report of this many bytes was copied into the buffer.
+    Report(usize),
+    TimedOut,
+    /// The source has no more reports.
+    Closed,
+}
+
+/// Anything that can hand out raw HID input reports, one at a time.
+pub trait ReportSource: Send {
+    /// Waits up to `timeout` for the next report and copies it into `buf`.
+    fn read_report(&mut self, buf: &mut [u8], timeout: Duration) -> HidResult<Read>;
+
+    /// When the report last read arrived.
+    fn received_at(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl ReportSource for HidDevice {
+    fn read_report(&mut self, buf: &mut [u8], timeout: Duration) -> HidResult<Read> {
+        let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // hidapi reads 0 bytes on timeout
+        match self.read_timeout(buf, millis)? {
+            0 => Ok(Read::TimedOut),
+            len => Ok(Read::Report(len)),
+        }
+    }
+}
+
+/// Parses a report written as whitespace-separated hex bytes, e.g. `01 e9 00 00`.
+pub fn parse_hex(line: &str) -> Result<Vec<u8>, ParseIntError> {
+    line.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect()
+}
+
+/// One scripted step of a replay.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayEntry {
+    Report(Vec<u8>),
+    /// Fails the read as if the device had been unplugged.
+    Disconnect,
+    /// Moves the replay's clock forward without waiting.
+    Wait(Duration),
+    /// Changes the memory backend's sink as another application would.
+    Sink(OutsideChange),
+}
+
+/// In-memory source replaying a fixed sequence of reports.
+///
+/// Reports are handed out without waiting, on a clock of their own that only `Wait` entries
+/// move, so timing-dependent behaviour replays the same way every time. Clones share the
+/// remaining script and the clock, so "reconnecting" to a replay continues where the previous
+/// connection left off.
+#[derive(Clone)]
+pub struct ReplaySource {
+    entries: Arc<Mutex<VecDeque<ReplayEntry>>>,
+    clock: Arc<Mutex<Instant>>,
+    /// Where `Sink` entries are applied.
+    mixer: Option<MemoryMixer>,
+}
+
+impl ReplaySource {
+    pub fn new(entries: Vec<ReplayEntry>) -> Self {
+        ReplaySource {
+            entries: Arc::new(Mutex::new(entries.into())),
+            clock: Arc::new(Mutex::new(Instant::now())),
+            mixer: None,
+        }
+    }
+
+    /// Applies the replay's sink changes to `mixer`; without one they are skipped.
+    pub fn with_mixer(mut self, mixer: MemoryMixer) -> Self {
+        self.mixer = Some(mixer);
+        self
+    }
+
+    /// Loads reports from a text file, one report per line written as hex bytes,
+    /// e.g. `01 e9 00 00`. A line reading `disconnect` simulates the device going away, and
+    /// a line starting with `+` and a number of milliseconds, e.g. `+40 01 e9`, arrives that
+    /// long after the previous one. A line starting with `sink`, e.g. `sink volume 30` or
+    /// `sink mute`, changes the memory backend's sink before the next report is read. Empty
+    /// lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        let mut reports = vec![];
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = match line.strip_prefix('+') {
+                Some(rest) => {
+                    let (delay, rest) =
+                        rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+                    let delay = delay
+                        .parse()
+                        .map_err(|e| format!("{}:{}: {}", path.display(), line_no + 1, e))?;
+                    reports.push(ReplayEntry::Wait(Duration::from_millis(delay)));
+                    rest.trim_start()
+                }
+                None => line,
+            };
+            if line.is_empty() {
+                continue;
+            }
+            if line == "disconnect" {
+                reports.push(ReplayEntry::Disconnect);
+                continue;
+            }
+            if let Some(change) = line.strip_prefix("sink ") {
+                let change = OutsideChange::parse(change)
+                    .map_err(|e| format!("{}:{}: {}", path.display(), line_no + 1, e))?;
+                reports.push(ReplayEntry::Sink(change));
+                continue;
+            }
+            let report = parse_hex(line)
+                .map_err(|e| format!("{}:{}: {}", path.display(), line_no + 1, e))?;
+            reports.push(ReplayEntry::Report(report));
+        }
+        Ok(Self::new(reports))
+    }
+}
+
+impl ReportSource for ReplaySource {
+    fn read_report(&mut self, buf: &mut [u8], _timeout: Duration) -> HidResult<Read> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut entry = entries.pop_front();
+        loop {
+            match entry {
+                Some(ReplayEntry::Wait(delay)) => *self.clock.lock().unwrap() += delay,
+                Some(ReplayEntry::Sink(change)) => match &self.mixer {
+                    Some(mixer) => mixer.change(change),
+                    None => eprintln!("Skipping sink change: not using the memory backend"),
+                },
+                _ => break,
+            }
+            entry = entries.pop_front();
+        }
+        match entry {
+            Some(ReplayEntry::Report(report)) => {
+                if report.len() > buf.len() {
+                    return Err(HidError::HidApiError {
+                        message: format!(
+                            "Report of {} bytes does not fit into {} byte buffer",
+                            report.len(),
+                            buf.len()
+                        ),
+                    });
+                }
+                buf[..report.len()].copy_from_slice(&report);
+                for byte in &mut buf[report.len()..] {
+                    *byte = 0;
+                }
+                Ok(Read::Report(report.len()))
+            }
+            Some(ReplayEntry::Disconnect) => Err(HidError::HidApiError {
+                message: String::from("Replayed disconnect"),
+            }),
+            Some(ReplayEntry::Wait(_)) | Some(ReplayEntry::Sink(_)) | None => Ok(Read::Closed),
+        }
+    }
+
+    fn received_at(&self) -> Instant {
+        *self.clock.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpulse_binding::volume::{Volume, VOLUME_NORM};
+
+    use super::*;
+
+    fn read(source: &mut ReplaySource) -> HidResult<Option<Vec<u8>>> {
+        let mut buf = [0xaa; 8];
+        match source.read_report(&mut buf, Duration::ZERO)? {
+            Read::Report(len) => Ok(Some(buf[..len].to_vec())),
+            Read::TimedOut | Read::Closed => Ok(None),
+        }
+    }
+
+    #[test]
+    fn parses_hex_bytes() {
+        assert_eq!(parse_hex("01 e9 0A"), Ok(vec![1, 0xe9, 0x0a]));
+        assert!(parse_hex("01 zz").is_err());
+    }
+
+    #[test]
+    fn replays_reports_in_order_then_closes() {
+        let mut source = ReplaySource::new(vec![
+            ReplayEntry::Report(vec![1, 233]),
+            ReplayEntry::Report(vec![5, 15, 0, 3]),
+        ]);
+        assert_eq!(read(&mut source).unwrap(), Some(vec![1, 233]));
+        assert_eq!(read(&mut source).unwrap(), Some(vec![5, 15, 0, 3]));
+        assert_eq!(read(&mut source).unwrap(), None);
+    }
+
+    #[test]
+    fn replays_a_disconnect_as_an_error() {
+        let mut source = ReplaySource::new(vec![
+            ReplayEntry::Disconnect,
+            ReplayEntry::Report(vec![1, 234]),
+        ]);
+        assert!(read(&mut source).is_err());
+        // a reconnected clone carries on after the disconnect
+        assert_eq!(read(&mut source.clone()).unwrap(), Some(vec![1, 234]));
+    }
+
+    #[test]
+    fn rejects_reports_larger_than_the_buffer() {
+        let mut source = ReplaySource::new(vec![ReplayEntry::Report(vec![0; 9])]);
+        assert!(read(&mut source).is_err());
+    }
+
+    /// Parses `contents` as a replay file.
+    fn from_file(name: &str, contents: &str) -> Result<ReplaySource, String> {
+        let path = std::env::temp_dir().join(format!("nommo-{}-{}.txt", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let source = ReplaySource::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        source
+    }
+
+    #[test]
+    fn reads_sink_changes_between_reports() {
+        let source = from_file(
+            "sink-changes",
+            "# turned up, then changed elsewhere\n\
+             01 e9\n\
+             +40 sink volume 30\n\
+             sink mute\n\
+             sink  unmute\n\
+             +10 01 ea\n",
+        )
+        .unwrap();
+        assert_eq!(
+            source.entries.lock().unwrap().iter().collect::<Vec<_>>(),
+            vec![
+                &ReplayEntry::Report(vec![1, 0xe9]),
+                &ReplayEntry::Wait(Duration::from_millis(40)),
+                &ReplayEntry::Sink(OutsideChange::Volume(30.0)),
+                &ReplayEntry::Sink(OutsideChange::Mute(true)),
+                &ReplayEntry::Sink(OutsideChange::Mute(false)),
+                &ReplayEntry::Wait(Duration::from_millis(10)),
+                &ReplayEntry::Report(vec![1, 0xea]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_sink_changes_by_line() {
+        let error = from_file("bad-sink", "01 e9\nsink volume loud\n")
+            .err()
+            .unwrap();
+        assert!(
+            error.ends_with(".txt:2: Invalid volume loud: invalid float literal"),
+            "{}",
+            error
+        );
+        let error = from_file("unknown-sink", "sink louder\n").err().unwrap();
+        assert!(
+            error.ends_with(".txt:1: Unknown sink change: louder"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn applies_sink_changes_to_its_mixer_when_read() {
+        let mixer = MemoryMixer::new(Volume(0), 2);
+        let mut source = ReplaySource::new(vec![
+            ReplayEntry::Sink(OutsideChange::Volume(25.0)),
+            ReplayEntry::Sink(OutsideChange::Mute(true)),
+            ReplayEntry::Report(vec![1, 233]),
+        ])
+        .with_mixer(mixer.clone());
+        assert_eq!(mixer.volumes().avg(), Volume(0));
+        assert_eq!(read(&mut source).unwrap(), Some(vec![1, 233]));
+        assert_eq!(mixer.volumes().avg(), Volume(VOLUME_NORM.0 / 4));
+        assert!(mixer.mute());
+    }
+}
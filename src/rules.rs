@@ -0,0 +1,458 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::report::parse_hex;
+use crate::NommoMsg;
+
+/// The message a rule decodes a report into.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Message {
+    VolUp,
+    VolDown,
+    EqValue,
+    /// A button press, with the button's number as the value.
+    Button,
+    Noop,
+}
+
+fn full_mask() -> u8 {
+    0xff
+}
+
+fn is_full_mask(mask: &u8) -> bool {
+    *mask == 0xff
+}
+
+fn is_zero(shift: &u8) -> bool {
+    *shift == 0
+}
+
+/// Requires `report[offset] & mask == value`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ByteMatch {
+    pub offset: usize,
+    pub value: u8,
+    #[serde(default = "full_mask", skip_serializing_if = "is_full_mask")]
+    pub mask: u8,
+}
+
+/// Extracts `(report[offset] & mask) >> shift` as the message value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Field {
+    pub offset: usize,
+    #[serde(default = "full_mask", skip_serializing_if = "is_full_mask")]
+    pub mask: u8,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub shift: u8,
+}
+
+/// Why a report could not be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The device sent a report without any bytes.
+    Empty,
+    /// The report filled the whole read buffer, so it may have been cut off.
+    Truncated { len: usize },
+    /// A rule matched, but the report ends before its value field.
+    Short { len: usize, needed: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty report"),
+            DecodeError::Truncated { len } => {
+                write!(f, "report of {} bytes may have been cut off", len)
+            }
+            DecodeError::Short { len, needed } => {
+                write!(f, "report of {} bytes, expected at least {}", len, needed)
+            }
+        }
+    }
+}
+
+/// Why a rule set was rejected: the rule at fault, counting from 0, and what is wrong with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleError {
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rule {}: {}", self.index + 1, self.message)
+    }
+}
+
+/// Decodes reports whose bytes all match into `message`.
+///
+/// With `report_id` set, the report's first byte must equal it and all offsets count from the
+/// byte after it; otherwise offsets count from the start of the report.
+///
+/// `samples` are captured reports, written as hex like replay files, that the rule set must
+/// decode with this rule; they are checked whenever the rules are loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub message: Message,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report_id: Option<u8>,
+    #[serde(rename = "match")]
+    pub bytes: Vec<ByteMatch>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Field>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<String>,
+}
+
+impl Rule {
+    /// The bytes offsets count from, or `None` if the report has another ID.
+    fn payload<'r>(&self, report: &'r [u8]) -> Option<&'r [u8]> {
+        match (self.report_id, report.split_first()) {
+            (None, _) => Some(report),
+            (Some(id), Some((first, payload))) if *first == id => Some(payload),
+            (Some(_), _) => None,
+        }
+    }
+
+    fn matches(&self, report: &[u8]) -> bool {
+        self.payload(report).is_some_and(|payload| {
+            self.bytes.iter().all(|byte| {
+                payload
+                    .get(byte.offset)
+                    .is_some_and(|value| value & byte.mask == byte.value)
+            })
+        })
+    }
+
+    /// `Ok(None)` if the rule does not match the report.
+    fn decode(&self, report: &[u8]) -> Result<Option<NommoMsg>, DecodeError> {
+        let payload = match self.payload(report) {
+            Some(payload) if self.matches(report) => payload,
+            _ => return Ok(None),
+        };
+        let value = match &self.value {
+            Some(field) => {
+                let byte = payload.get(field.offset).ok_or(DecodeError::Short {
+                    len: report.len(),
+                    needed: report.len() - payload.len() + field.offset + 1,
+                })?;
+                Some((byte & field.mask) >> field.shift)
+            }
+            None => None,
+        };
+        let msg = match (self.message, value) {
+            (Message::VolUp, _) => NommoMsg::VolUp,
+            (Message::VolDown, _) => NommoMsg::VolDown,
+            (Message::EqValue, Some(value)) => NommoMsg::EqValue(value),
+            (Message::Button, Some(value)) => NommoMsg::Button(value),
+            // validation rejects rules without the value field their message needs
+            (Message::EqValue | Message::Button, None) | (Message::Noop, _) => NommoMsg::Noop,
+        };
+        Ok(Some(msg))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.bytes.is_empty() && self.report_id.is_none() {
+            return Err(format!("{:?} rule matches every report", self.message));
+        }
+        if let Some(byte) = self.bytes.iter().find(|byte| byte.value & !byte.mask != 0) {
+            return Err(format!(
+                "{:?} rule can never match byte {}: value {:#04x} has bits outside mask {:#04x}",
+                self.message, byte.offset, byte.value, byte.mask
+            ));
+        }
+        let carries_value = matches!(self.message, Message::EqValue | Message::Button);
+        match (&self.value, self.message) {
+            (None, message) if carries_value => {
+                Err(format!("{:?} rule needs a value field", message))
+            }
+            (Some(_), message) if !carries_value => {
+                Err(format!("{:?} rule does not carry a value", message))
+            }
+            (Some(field), _) if field.shift > 7 => {
+                Err(format!("value shift {} is wider than a byte", field.shift))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    rule: Vec<Rule>,
+}
+
+/// Parses and validates a rule file made of `[[rule]]` tables.
+pub fn parse(contents: &str) -> Result<Vec<Rule>, String> {
+    let file: RuleFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+    validate(&file.rule).map_err(|e| e.to_string())?;
+    Ok(file.rule)
+}
+
+/// Decodes a report with the first matching rule, or returns `None` if no rule matches.
+pub fn decode<'a>(
+    rules: impl IntoIterator<Item = &'a Rule>,
+    report: &[u8],
+) -> Result<Option<NommoMsg>, DecodeError> {
+    if report.is_empty() {
+        return Err(DecodeError::Empty);
+    }
+    for rule in rules {
+        if let Some(msg) = rule.decode(report)? {
+            return Ok(Some(msg));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks every rule on its own, and that each sample is decoded by the rule it belongs to.
+pub fn validate(rules: &[Rule]) -> Result<(), RuleError> {
+    for (index, rule) in rules.iter().enumerate() {
+        let error = |message| RuleError { index, message };
+        rule.validate().map_err(error)?;
+        for sample in &rule.samples {
+            let report =
+                parse_hex(sample).map_err(|e| error(format!("sample `{}`: {}", sample, e)))?;
+            let first = rules.iter().position(|other| other.matches(&report));
+            if first != Some(index) {
+                let decoder = first.map_or(String::from("no rule"), |other| {
+                    format!("rule {}", other + 1)
+                });
+                return Err(error(format!(
+                    "sample `{}` is decoded by {}",
+                    sample, decoder
+                )));
+            }
+            rule.decode(&report)
+                .map_err(|e| error(format!("sample `{}`: {}", sample, e)))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile;
+
+    /// Report `id` with `bytes`, padded with zeros to `len` bytes.
+    fn padded(id: u8, bytes: &[u8], len: usize) -> Vec<u8> {
+        let mut report = vec![id];
+        report.extend_from_slice(bytes);
+        report.resize(len, 0);
+        report
+    }
+
+    fn decode_builtin(report: &[u8]) -> Result<Option<NommoMsg>, DecodeError> {
+        decode(&profile::DEFAULT.rules().unwrap(), report)
+    }
+
+    #[test]
+    fn decodes_reports_of_any_length() {
+        for len in [8, 16, 64] {
+            assert_eq!(
+                decode_builtin(&padded(1, &[0xe9], len)),
+                Ok(Some(NommoMsg::VolUp))
+            );
+            assert_eq!(
+                decode_builtin(&padded(5, &[0x0f, 0, 3], len)),
+                Ok(Some(NommoMsg::EqValue(3)))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_empty_reports() {
+        assert_eq!(decode_builtin(&[]), Err(DecodeError::Empty));
+    }
+
+    #[test]
+    fn rejects_reports_ending_before_the_value() {
+        assert_eq!(
+            decode_builtin(&[5, 0x0f, 0]),
+            Err(DecodeError::Short { len: 3, needed: 4 })
+        );
+    }
+
+    #[test]
+    fn skips_rules_for_other_report_ids() {
+        assert_eq!(decode_builtin(&padded(2, &[0xe9], 16)), Ok(None));
+        // the ID byte is not part of the payload offsets count in
+        assert_eq!(decode_builtin(&[0xe9, 0xe9]), Ok(None));
+    }
+
+    #[test]
+    fn matches_from_the_start_without_a_report_id() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            message = "vol-up"
+            match = [{ offset = 1, value = 0xe9 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(decode(&rules, &[7, 0xe9]), Ok(Some(NommoMsg::VolUp)));
+    }
+
+    /// The error `contents` is rejected with.
+    fn rejection(contents: &str) -> String {
+        parse(contents).unwrap_err()
+    }
+
+    #[test]
+    fn rejects_a_rule_matching_every_report() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "vol-up"
+                match = []
+                "#
+            ),
+            "rule 1: VolUp rule matches every report"
+        );
+    }
+
+    #[test]
+    fn rejects_a_value_with_bits_outside_its_mask() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "vol-up"
+                report_id = 1
+                match = [{ offset = 0, value = 0xe9 }]
+
+                [[rule]]
+                message = "vol-down"
+                match = [{ offset = 2, value = 0x1a, mask = 0x0f }]
+                "#
+            ),
+            "rule 2: VolDown rule can never match byte 2: value 0x1a has bits outside mask 0x0f"
+        );
+    }
+
+    #[test]
+    fn rejects_value_messages_without_a_value_field() {
+        for (message, name) in [("eq-value", "EqValue"), ("button", "Button")] {
+            let error = rejection(&format!(
+                r#"
+                [[rule]]
+                message = "{}"
+                report_id = 5
+                match = [{{ offset = 0, value = 0x0f }}]
+                "#,
+                message
+            ));
+            assert_eq!(error, format!("rule 1: {} rule needs a value field", name));
+        }
+    }
+
+    #[test]
+    fn rejects_a_value_field_on_vol_up() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "vol-up"
+                report_id = 1
+                match = [{ offset = 0, value = 0xe9 }]
+                value = { offset = 1 }
+                "#
+            ),
+            "rule 1: VolUp rule does not carry a value"
+        );
+    }
+
+    #[test]
+    fn rejects_a_shift_wider_than_a_byte() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "eq-value"
+                report_id = 5
+                match = []
+                value = { offset = 2, shift = 8 }
+                "#
+            ),
+            "rule 1: value shift 8 is wider than a byte"
+        );
+    }
+
+    #[test]
+    fn rejects_a_sample_decoded_by_an_earlier_rule() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "noop"
+                report_id = 1
+                match = []
+
+                [[rule]]
+                message = "vol-up"
+                report_id = 1
+                match = [{ offset = 0, value = 0xe9 }]
+                samples = ["01 e9 00"]
+                "#
+            ),
+            "rule 2: sample `01 e9 00` is decoded by rule 1"
+        );
+    }
+
+    #[test]
+    fn rejects_a_sample_decoded_by_no_rule() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "vol-up"
+                report_id = 1
+                match = [{ offset = 0, value = 0xe9 }]
+                samples = ["01 ea 00"]
+                "#
+            ),
+            "rule 1: sample `01 ea 00` is decoded by no rule"
+        );
+    }
+
+    #[test]
+    fn rejects_a_malformed_sample() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "vol-up"
+                report_id = 1
+                match = [{ offset = 0, value = 0xe9 }]
+                samples = ["01 e9 zz"]
+                "#
+            ),
+            "rule 1: sample `01 e9 zz`: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn rejects_a_sample_ending_before_the_value() {
+        assert_eq!(
+            rejection(
+                r#"
+                [[rule]]
+                message = "eq-value"
+                report_id = 5
+                match = [{ offset = 0, value = 0x0f }]
+                value = { offset = 2 }
+                samples = ["05 0f 00"]
+                "#
+            ),
+            "rule 1: sample `05 0f 00`: report of 3 bytes, expected at least 4"
+        );
+    }
+}
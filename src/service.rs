@@ -0,0 +1,536 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use libpulse_binding::volume::ChannelVolumes;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::Connection;
+use zbus::{interface, SignalContext};
+
+use crate::accel::Accelerator;
+#[cfg(test)]
+use crate::audio::MemoryMixer;
+use crate::audio::{self, AudioError, Connect};
+use crate::config::{Action, Config};
+use crate::driver::{self, Outputs, Settings};
+use crate::error::Error;
+use crate::notify::Notifier;
+use crate::reload::ConfigWatcher;
+#[cfg(test)]
+use crate::session::SessionBus;
+use crate::NommoMsg;
+
+const BUS_NAME: &str = "org.nommo.VolDriver";
+const OBJECT_PATH: &str = "/org/nommo/VolDriver";
+
+/// What the driver last did, as published on the bus.
+#[derive(Debug, Default)]
+struct State {
+    devices: Vec<String>,
+    sink: String,
+    volume: f64,
+    muted: bool,
+    /// Sink set over the bus, taking precedence over the configured ones.
+    sink_override: Option<String>,
+    /// Sink named in the configuration, empty for the default sink.
+    configured_sink: String,
+}
+
+impl State {
+    /// Starts out targeting the configured sink, which commands over the bus go to; bindings
+    /// only give their own devices a sink, so theirs shows once such a device changes it.
+    fn new(config: &Config) -> Self {
+        let configured_sink = config.volume.sink.clone().unwrap_or_default();
+        State {
+            sink: configured_sink.clone(),
+            configured_sink,
+            ..State::default()
+        }
+    }
+
+    /// Sets the sink override, going back to the configured sink for an empty name.
+    fn override_sink(&mut self, name: String) {
+        self.sink_override = Some(name).filter(|name| !name.is_empty());
+        self.sink = match &self.sink_override {
+            Some(name) => name.clone(),
+            None => self.configured_sink.clone(),
+        };
+    }
+}
+
+struct DriverInterface {
+    state: Arc<Mutex<State>>,
+    requests: Sender<Action>,
+}
+
+impl DriverInterface {
+    fn request(&self, action: Action) -> zbus::fdo::Result<()> {
+        self.requests
+            .send(action)
+            .map_err(|_| zbus::fdo::Error::Failed(String::from("Driver is shutting down")))
+    }
+}
+
+#[interface(name = "org.nommo.VolDriver")]
+impl DriverInterface {
+    fn volume_up(&self) -> zbus::fdo::Result<()> {
+        self.request(Action::VolumeUp)
+    }
+
+    fn volume_down(&self) -> zbus::fdo::Result<()> {
+        self.request(Action::VolumeDown)
+    }
+
+    fn toggle_mute(&self) -> zbus::fdo::Result<()> {
+        self.request(Action::ToggleMute)
+    }
+
+    /// Makes every device control `name`; an empty name goes back to the configured sinks.
+    async fn set_target_sink(
+        &self,
+        name: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> zbus::fdo::Result<()> {
+        self.state.lock().unwrap().override_sink(name);
+        Ok(self.target_sink_changed(&ctxt).await?)
+    }
+
+    #[zbus(property)]
+    fn connected_devices(&self) -> Vec<String> {
+        self.state.lock().unwrap().devices.clone()
+    }
+
+    /// The sink last changed by a device or a command, or until then the one commands go to:
+    /// the configured sink, ignoring per-device bindings, or empty for the default sink.
+    #[zbus(property)]
+    fn target_sink(&self) -> String {
+        self.state.lock().unwrap().sink.clone()
+    }
+
+    /// Average volume over all channels, in percent.
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.state.lock().unwrap().volume
+    }
+
+    #[zbus(property)]
+    fn muted(&self) -> bool {
+        self.state.lock().unwrap().muted
+    }
+
+    /// Sent after every change to a sink, with its volume in percent.
+    #[zbus(signal)]
+    async fn sink_changed(
+        ctxt: &SignalContext<'_>,
+        sink: &str,
+        volume: f64,
+        muted: bool,
+    ) -> zbus::Result<()>;
+}
+
+/// Publishes the driver's status on the session bus and takes volume commands from it.
+///
+/// Clones share the connection, so every device handler can report its changes.
+#[derive(Clone)]
+pub struct Service {
+    connection: Connection,
+    state: Arc<Mutex<State>>,
+}
+
+impl Service {
+    /// Claims the bus name and starts a worker with its own audio connection that carries out
+    /// the commands received.
+    pub fn start(
+        config: &Config,
+        verbose: bool,
+        watcher: &ConfigWatcher,
+        notifier: Option<Notifier>,
+        connect: Connect,
+    ) -> Result<Self, String> {
+        Self::start_on(
+            Builder::session(),
+            config,
+            verbose,
+            watcher,
+            notifier,
+            connect,
+        )
+    }
+
+    fn start_on(
+        bus: zbus::Result<Builder<'static>>,
+        config: &Config,
+        verbose: bool,
+        watcher: &ConfigWatcher,
+        notifier: Option<Notifier>,
+        connect: Connect,
+    ) -> Result<Self, String> {
+        let state = Arc::new(Mutex::new(State::new(config)));
+        let (requests, received) = mpsc::channel();
+        let interface = DriverInterface {
+            state: state.clone(),
+            requests,
+        };
+        let connection = bus
+            .and_then(|builder| builder.name(BUS_NAME))
+            .and_then(|builder| builder.serve_at(OBJECT_PATH, interface))
+            .and_then(|builder| builder.build())
+            .map_err(|e| format!("Cannot publish {} on the session bus: {}", BUS_NAME, e))?;
+
+        let service = Service { connection, state };
+        let worker = service.clone();
+        let config = config.clone();
+        let watcher = watcher.clone();
+        thread::spawn(move || worker.serve(received, config, verbose, watcher, notifier, connect));
+        Ok(service)
+    }
+
+    fn serve(
+        self,
+        received: Receiver<Action>,
+        config: Config,
+        verbose: bool,
+        mut watcher: ConfigWatcher,
+        notifier: Option<Notifier>,
+        connect: Connect,
+    ) {
+        let mut settings = Settings::new(&config, verbose, None);
+        let mut outputs = None;
+        let mut accelerator = Accelerator::default();
+        for action in received {
+            if let Some(Ok(config)) = watcher.poll() {
+                settings = Settings::new(&config, verbose, None);
+                self.state.lock().unwrap().configured_sink = config.volume.sink.unwrap_or_default();
+            }
+            let outputs = match &mut outputs {
+                Some(outputs) => outputs,
+                None => match connect(&config.backend) {
+                    Ok(audio) => {
+                        outputs.insert(Outputs::new(audio, Some(self.clone()), notifier.clone()))
+                    }
+                    Err(error) => {
+                        eprintln!("{}", Error::Audio(AudioError::Disconnected(error)));
+                        continue;
+                    }
+                },
+            };
+            let step = settings.step_for(&action, Instant::now(), &mut accelerator);
+            if let Err(error) = driver::dispatch(&NommoMsg::Noop, &action, step, outputs, &settings)
+            {
+                eprintln!("{}", error);
+            }
+        }
+    }
+
+    /// The sink set over the bus, if any.
+    pub fn sink_override(&self) -> Option<String> {
+        self.state.lock().unwrap().sink_override.clone()
+    }
+
+    pub fn device_connected(&self, path: &str) {
+        self.state.lock().unwrap().devices.push(path.to_string());
+        self.publish(|interface, ctxt| zbus::block_on(interface.connected_devices_changed(ctxt)));
+    }
+
+    pub fn device_disconnected(&self, path: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .retain(|device| device != path);
+        self.publish(|interface, ctxt| zbus::block_on(interface.connected_devices_changed(ctxt)));
+    }
+
+    /// Records the state of a sink after a change and announces it.
+    pub fn update(&self, sink: &str, volumes: &ChannelVolumes, muted: bool) {
+        let volume = audio::percent(volumes);
+        {
+            let mut state = self.state.lock().unwrap();
+            state.sink = sink.to_string();
+            state.volume = volume;
+            state.muted = muted;
+        }
+        self.publish(|interface, ctxt| {
+            zbus::block_on(async {
+                interface.target_sink_changed(ctxt).await?;
+                interface.volume_changed(ctxt).await?;
+                interface.muted_changed(ctxt).await?;
+                DriverInterface::sink_changed(ctxt, sink, volume, muted).await
+            })
+        });
+    }
+
+    fn publish(&self, emit: impl FnOnce(&DriverInterface, &SignalContext<'_>) -> zbus::Result<()>) {
+        let result = self
+            .connection
+            .object_server()
+            .interface::<_, DriverInterface>(OBJECT_PATH)
+            .and_then(|interface| emit(&interface.get(), interface.signal_context()));
+        if let Err(error) = result {
+            eprintln!("Cannot publish status on the session bus: {}", error);
+        }
+    }
+}
+
+/// A bus daemon of its own, so tests neither need nor disturb a session bus. The daemon stops
+/// when this is dropped.
+#[cfg(test)]
+pub struct PrivateBus {
+    daemon: std::process::Child,
+    address: String,
+}
+
+#[cfg(test)]
+impl PrivateBus {
+    /// Starts `dbus-daemon`, or returns `None` if it is not installed.
+    pub fn start() -> Option<Self> {
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+
+        let mut daemon = match Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(error) => {
+                eprintln!("Skipping a bus test, cannot start dbus-daemon: {}", error);
+                return None;
+            }
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some(PrivateBus {
+            daemon,
+            address: address.trim().to_string(),
+        })
+    }
+
+    /// The driver's service on this bus, connecting to `mixer` for the `memory` backend.
+    pub fn service(&self, config: &Config, mixer: &MemoryMixer) -> Service {
+        let bus = Builder::address(self.address.as_str());
+        let connect = audio::connector(mixer.clone());
+        Service::start_on(bus, config, false, &ConfigWatcher::idle(), None, connect).unwrap()
+    }
+
+    /// A session bus connection to this bus instead.
+    pub fn session(&self) -> SessionBus {
+        SessionBus::at(&self.address)
+    }
+
+    /// Claims `name` and serves `object` at `path` until the returned connection is dropped,
+    /// standing in for another application.
+    pub fn serve(
+        &self,
+        name: &str,
+        path: &str,
+        object: impl zbus::object_server::Interface,
+    ) -> Connection {
+        Builder::address(self.address.as_str())
+            .and_then(|builder| builder.name(name))
+            .and_then(|builder| builder.serve_at(path, object))
+            .and_then(|builder| builder.build())
+            .unwrap()
+    }
+
+    /// A client of the driver's object that reads properties afresh every time.
+    pub fn driver(&self) -> zbus::blocking::Proxy<'static> {
+        let connection = Builder::address(self.address.as_str())
+            .and_then(|builder| builder.build())
+            .unwrap();
+        zbus::blocking::proxy::Builder::new(&connection)
+            .destination(BUS_NAME)
+            .and_then(|builder| builder.path(OBJECT_PATH))
+            .and_then(|builder| builder.interface(BUS_NAME))
+            .map(|builder| builder.cache_properties(zbus::proxy::CacheProperties::No))
+            .and_then(|builder| builder.build())
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpulse_binding::volume::{Volume, VOLUME_NORM};
+
+    use super::*;
+
+    fn interface() -> (DriverInterface, Receiver<Action>) {
+        let (requests, received) = mpsc::channel();
+        let interface = DriverInterface {
+            state: Arc::default(),
+            requests,
+        };
+        (interface, received)
+    }
+
+    #[test]
+    fn methods_queue_their_actions() {
+        let (interface, received) = interface();
+        interface.volume_up().unwrap();
+        interface.volume_down().unwrap();
+        interface.toggle_mute().unwrap();
+        drop(interface);
+        assert_eq!(
+            received.iter().collect::<Vec<_>>(),
+            vec![Action::VolumeUp, Action::VolumeDown, Action::ToggleMute]
+        );
+    }
+
+    #[test]
+    fn methods_fail_once_the_worker_is_gone() {
+        let (interface, received) = interface();
+        drop(received);
+        assert!(interface.volume_up().is_err());
+    }
+
+    #[test]
+    fn properties_show_the_state() {
+        let (interface, _received) = interface();
+        *interface.state.lock().unwrap() = State {
+            devices: vec![String::from("/dev/hidraw3")],
+            sink: String::from("speakers"),
+            volume: 42.0,
+            muted: true,
+            ..State::default()
+        };
+        assert_eq!(interface.connected_devices(), vec!["/dev/hidraw3"]);
+        assert_eq!(interface.target_sink(), "speakers");
+        assert_eq!(interface.volume(), 42.0);
+        assert!(interface.muted());
+    }
+
+    #[test]
+    fn an_empty_sink_name_goes_back_to_the_configured_sink() {
+        let mut config = Config::default();
+        config.volume.sink = Some(String::from("speakers"));
+        let mut state = State::new(&config);
+        assert_eq!(state.sink, "speakers");
+        state.override_sink(String::from("headphones"));
+        assert_eq!(state.sink_override.as_deref(), Some("headphones"));
+        assert_eq!(state.sink, "headphones");
+        state.override_sink(String::new());
+        assert_eq!(state.sink_override, None);
+        assert_eq!(state.sink, "speakers");
+
+        let mut state = State::new(&Config::default());
+        state.override_sink(String::from("headphones"));
+        state.override_sink(String::new());
+        assert_eq!(state.sink, "");
+    }
+
+    /// A mixer for services whose worker is never asked to connect.
+    fn idle_mixer() -> MemoryMixer {
+        MemoryMixer::new(Volume(0), 2)
+    }
+
+    fn volumes(percent: u32) -> ChannelVolumes {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set(2, Volume(percent * VOLUME_NORM.0 / 100));
+        volumes
+    }
+
+    #[test]
+    fn updates_are_published_as_properties_and_a_signal() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let service = bus.service(&Config::default(), &idle_mixer());
+        let driver = bus.driver();
+        let mut signals = driver.receive_signal("SinkChanged").unwrap();
+
+        service.update("speakers", &volumes(25), true);
+        let signal = signals.next().unwrap();
+        let body: (String, f64, bool) = signal.body().deserialize().unwrap();
+        assert_eq!(body, (String::from("speakers"), 25.0, true));
+        assert_eq!(
+            driver.get_property::<String>("TargetSink").unwrap(),
+            "speakers"
+        );
+        assert_eq!(driver.get_property::<f64>("Volume").unwrap(), 25.0);
+        assert!(driver.get_property::<bool>("Muted").unwrap());
+    }
+
+    #[test]
+    fn connected_devices_are_published() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let service = bus.service(&Config::default(), &idle_mixer());
+        let driver = bus.driver();
+        let devices = || {
+            driver
+                .get_property::<Vec<String>>("ConnectedDevices")
+                .unwrap()
+        };
+        assert!(devices().is_empty());
+
+        service.device_connected("/dev/hidraw3");
+        service.device_connected("/dev/hidraw4");
+        assert_eq!(devices(), vec!["/dev/hidraw3", "/dev/hidraw4"]);
+        service.device_disconnected("/dev/hidraw3");
+        assert_eq!(devices(), vec!["/dev/hidraw4"]);
+    }
+
+    #[test]
+    fn the_target_sink_is_set_and_cleared_over_the_bus() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let mut config = Config::default();
+        config.volume.sink = Some(String::from("speakers"));
+        let service = bus.service(&config, &idle_mixer());
+        let driver = bus.driver();
+        let target = || driver.get_property::<String>("TargetSink").unwrap();
+        assert_eq!(target(), "speakers");
+
+        driver
+            .call::<_, _, ()>("SetTargetSink", &("headphones",))
+            .unwrap();
+        assert_eq!(service.sink_override().as_deref(), Some("headphones"));
+        assert_eq!(target(), "headphones");
+
+        driver.call::<_, _, ()>("SetTargetSink", &("",)).unwrap();
+        assert_eq!(service.sink_override(), None);
+        assert_eq!(target(), "speakers");
+    }
+
+    #[test]
+    fn commands_over_the_bus_change_the_volume() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let mut config = Config::default();
+        config.backend.name = String::from("memory");
+        let mixer = audio::memory_mixer(&config.backend);
+        let _service = bus.service(&config, &mixer);
+        let driver = bus.driver();
+        let mut signals = driver.receive_signal("SinkChanged").unwrap();
+
+        driver.call::<_, _, ()>("VolumeUp", &()).unwrap();
+        let signal = signals.next().unwrap();
+        let (_, volume, muted): (String, f64, bool) = signal.body().deserialize().unwrap();
+        assert_eq!(volume, audio::percent(&mixer.volumes()));
+        assert!(volume > 50.0);
+        assert!(!muted);
+
+        driver.call::<_, _, ()>("ToggleMute", &()).unwrap();
+        signals.next().unwrap();
+        assert!(mixer.mute());
+    }
+}
@@ -0,0 +1,84 @@
+use zbus::blocking::Connection;
+
+/// A session bus connection made on first use and dropped after a failed call, as the bus
+/// may have gone away, so the next call reconnects.
+#[derive(Default)]
+pub struct SessionBus {
+    connection: Option<Connection>,
+    /// Bus to connect to instead of the session bus, in tests.
+    #[cfg(test)]
+    address: Option<String>,
+}
+
+impl SessionBus {
+    /// Connects to the bus at `address` instead of the session bus.
+    #[cfg(test)]
+    pub fn at(address: &str) -> Self {
+        SessionBus {
+            connection: None,
+            address: Some(address.to_string()),
+        }
+    }
+
+    /// Makes `call` over the connection, connecting first if there is none.
+    pub fn call<T>(
+        &mut self,
+        call: impl FnOnce(&Connection) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self
+                .connect()
+                .map_err(|e| format!("Cannot connect to the session bus: {}", e))?,
+        };
+        let result = call(&connection);
+        if result.is_ok() {
+            self.connection = Some(connection);
+        }
+        result
+    }
+
+    fn connect(&self) -> zbus::Result<Connection> {
+        #[cfg(test)]
+        if let Some(address) = &self.address {
+            return zbus::blocking::connection::Builder::address(address.as_str())?.build();
+        }
+        Connection::session()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::PrivateBus;
+
+    fn unique_name(connection: &Connection) -> Result<String, String> {
+        Ok(connection.unique_name().unwrap().to_string())
+    }
+
+    #[test]
+    fn keeps_the_connection_until_a_call_fails() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let mut session = bus.session();
+        let first = session.call(unique_name).unwrap();
+        assert_eq!(session.call(unique_name).unwrap(), first);
+
+        let failed = session.call(|_| -> Result<(), String> { Err(String::from("gone")) });
+        assert_eq!(failed, Err(String::from("gone")));
+        assert_ne!(session.call(unique_name).unwrap(), first);
+    }
+
+    #[test]
+    fn reports_a_failed_connection() {
+        let mut session = SessionBus::at("unix:path=/nonexistent/bus");
+        let error = session.call(unique_name).unwrap_err();
+        assert!(
+            error.starts_with("Cannot connect to the session bus: "),
+            "{}",
+            error
+        );
+    }
+}
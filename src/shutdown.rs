@@ -0,0 +1,33 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+/// Set once SIGTERM or SIGINT arrives; every loop checks it between events and winds down.
+///
+/// A second signal ends the process right away, for when winding down hangs.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn on_signals() -> io::Result<Self> {
+        let shutdown = Shutdown::default();
+        for signal in [SIGTERM, SIGINT] {
+            flag::register_conditional_shutdown(signal, 1, shutdown.requested.clone())?;
+            flag::register(signal, shutdown.requested.clone())?;
+        }
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,644 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use hidapi::HidError;
+
+use crate::audio::{AudioError, Connect};
+use crate::config::Config;
+use crate::device::{Backoff, DeviceBus, DeviceId};
+use crate::driver::{handle_device, Control, Outputs, Settings, TICK};
+use crate::error::Error;
+use crate::hotplug::HotplugMonitor;
+use crate::notify::Notifier;
+use crate::profile::Profile;
+use crate::report::ReportSource;
+use crate::rules::Rule;
+use crate::service::Service;
+use crate::shutdown::Shutdown;
+
+/// How long to wait between device scans when no hotplug event arrives.
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A device whose handler failed, left alone until `at`.
+struct Retry {
+    backoff: Backoff,
+    at: Instant,
+}
+
+/// The devices handlers are running for, and those waiting to be retried after a failure.
+#[derive(Default)]
+struct Devices {
+    active: HashSet<DeviceId>,
+    failed: HashMap<DeviceId, Retry>,
+}
+
+impl Devices {
+    /// Whether `id` has no handler and is not waiting out a backoff delay.
+    fn ready(&self, id: &DeviceId, now: Instant) -> bool {
+        !self.active.contains(id) && self.failed.get(id).is_none_or(|retry| retry.at <= now)
+    }
+
+    /// Puts off the next attempt at `id`, twice as long as the last time.
+    fn fail(&mut self, id: &DeviceId, now: Instant) {
+        let retry = self.failed.entry(id.clone()).or_insert_with(|| Retry {
+            backoff: Backoff::new(),
+            at: now,
+        });
+        retry.at = now + retry.backoff.next_delay();
+    }
+
+    /// Forgets the failures of devices that are gone, so they attach at once when plugged back in.
+    fn forget_missing(&mut self, present: &HashSet<DeviceId>) {
+        self.failed.retain(|id, _| present.contains(id));
+    }
+}
+
+type SharedDevices = Arc<Mutex<Devices>>;
+
+/// What every handler thread starts from.
+#[derive(Clone)]
+pub struct HandlerContext {
+    pub config: Config,
+    pub verbose: bool,
+    pub control: Control,
+    pub service: Option<Service>,
+    pub notifier: Option<Notifier>,
+    /// Connects a handler to the audio backend named in the configuration.
+    pub connect: Connect,
+}
+
+impl HandlerContext {
+    pub fn new(
+        config: &Config,
+        verbose: bool,
+        control: Control,
+        service: Option<Service>,
+        notifier: Option<Notifier>,
+        connect: Connect,
+    ) -> Self {
+        HandlerContext {
+            config: config.clone(),
+            verbose,
+            control,
+            service,
+            notifier,
+            connect,
+        }
+    }
+}
+
+/// Waits for a hotplug event or for `SCAN_INTERVAL` to pass, checking for a shutdown every tick.
+fn wait_for_scan(monitor: &mut dyn HotplugMonitor, shutdown: &Shutdown) {
+    let until = Instant::now() + SCAN_INTERVAL;
+    while !shutdown.requested() {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() || monitor.wait(left.min(TICK)) {
+            return;
+        }
+    }
+}
+
+/// Runs one handler thread per HID interface on `bus` with a matching profile, attaching
+/// devices as they appear.
+///
+/// Each handler owns its own audio backend connection and ends on its own when its device
+/// goes away. A device whose handler fails, or that cannot be opened, is retried with an
+/// exponential backoff for as long as it stays plugged in. Unless `config.device.wait` is set,
+/// finding no device on the first scan is an error; after that the supervisor keeps scanning
+/// until a shutdown is requested, and then waits for the handlers to finish.
+pub fn supervise(
+    context: &HandlerContext,
+    bus: &mut dyn DeviceBus,
+    monitor: &mut dyn HotplugMonitor,
+) -> Result<(), Error> {
+    let (config, control) = (&context.config, &context.control);
+    let devices = SharedDevices::default();
+    let mut handlers: Vec<JoinHandle<()>> = Vec::new();
+    let mut first_scan = true;
+
+    loop {
+        if control.shutdown.requested() {
+            eprintln!("Shutting down");
+            for handler in handlers {
+                let _ = handler.join();
+            }
+            return Ok(());
+        }
+        handlers.retain(|handler| !handler.is_finished());
+
+        let interfaces = bus.scan().unwrap_or_else(|error| {
+            eprintln!("Cannot enumerate devices: {}", error);
+            vec![]
+        });
+        let mut present = HashSet::new();
+        for interface in interfaces {
+            let profile = match Profile::select(&interface, &config.device) {
+                Some(profile) => profile,
+                None => continue,
+            };
+            let id = interface.id.clone();
+            present.insert(id.clone());
+            if !devices.lock().unwrap().ready(&id, Instant::now()) {
+                continue;
+            }
+            let rules = profile.rules().map_err(Error::Config)?;
+            match bus.open(&interface) {
+                Ok(source) => {
+                    eprintln!("Device connected: {} ({})", id.path, profile.name);
+                    handlers.push(spawn_handler(source, id, rules, context, &devices));
+                }
+                Err(error) => {
+                    if context.verbose {
+                        eprintln!("Cannot open {}: {}", id.path, error);
+                    }
+                    devices.lock().unwrap().fail(&id, Instant::now());
+                }
+            }
+        }
+        devices.lock().unwrap().forget_missing(&present);
+
+        // a handler may already have ended, so count the ones started rather than the active ones
+        if first_scan && handlers.is_empty() {
+            if !config.device.wait {
+                return Err(HidError::HidApiError {
+                    message: String::from("No matching device connected"),
+                }
+                .into());
+            }
+            eprintln!("Waiting for device");
+        }
+        first_scan = false;
+        wait_for_scan(monitor, &control.shutdown);
+    }
+}
+
+fn spawn_handler(
+    source: Box<dyn ReportSource>,
+    id: DeviceId,
+    rules: Vec<Rule>,
+    context: &HandlerContext,
+    devices: &SharedDevices,
+) -> JoinHandle<()> {
+    devices.lock().unwrap().active.insert(id.clone());
+    let HandlerContext {
+        config,
+        verbose,
+        mut control,
+        service,
+        notifier,
+        connect,
+    } = context.clone();
+    let devices = devices.clone();
+
+    thread::spawn(move || {
+        if let Some(service) = &service {
+            service.device_connected(&id.path);
+        }
+        let result = match connect(&config.backend) {
+            Ok(audio) => {
+                let mut outputs = Outputs::new(audio, service.clone(), notifier);
+                let mut settings = Settings::new(&config, verbose, Some(id.clone()));
+                handle_device(source, &rules, &mut outputs, &mut settings, &mut control)
+            }
+            Err(error) => Err(Error::Audio(AudioError::Disconnected(error))),
+        };
+        if let Some(service) = &service {
+            service.device_disconnected(&id.path);
+        }
+        let mut devices = devices.lock().unwrap();
+        devices.active.remove(&id);
+        match result {
+            Ok(()) => {
+                eprintln!("Device closed: {}", id.path);
+                devices.failed.remove(&id);
+            }
+            Err(error) => {
+                eprintln!("{}: {}", id.path, error);
+                devices.fail(&id, Instant::now());
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use hidapi::HidResult;
+    use libpulse_binding::volume::ChannelVolumes;
+
+    use super::*;
+    use crate::audio::{AudioBackend, Call, RecordingMixer, Sink};
+    use crate::config::BackendConfig;
+    use crate::config::Binding;
+    use crate::curve::Curve;
+    use crate::device::Interface;
+    use crate::reload::ConfigWatcher;
+    use crate::report::{Read, ReplayEntry, ReplaySource};
+
+    fn id(path: &str) -> DeviceId {
+        DeviceId {
+            path: path.to_string(),
+            serial: None,
+        }
+    }
+
+    fn at(percent: f64) -> ChannelVolumes {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set(2, Curve::Cubic.volume(percent / 100.0));
+        volumes
+    }
+
+    /// Replays a script, requesting a shutdown once it runs out.
+    struct Ending {
+        source: ReplaySource,
+        shutdown: Shutdown,
+    }
+
+    impl ReportSource for Ending {
+        fn read_report(&mut self, buf: &mut [u8], timeout: Duration) -> HidResult<Read> {
+            let read = self.source.read_report(buf, timeout)?;
+            if read == Read::Closed {
+                self.shutdown.request();
+            }
+            Ok(read)
+        }
+    }
+
+    /// A bus with one Nommo 2, plugged in after `absent` scans, that fails to open `failures`
+    /// times and then replays `source`, picking up where the last connection left off.
+    struct FakeBus {
+        absent: usize,
+        failures: usize,
+        opens: usize,
+        source: ReplaySource,
+        shutdown: Shutdown,
+    }
+
+    impl FakeBus {
+        fn new(entries: Vec<ReplayEntry>, shutdown: &Shutdown) -> Self {
+            FakeBus {
+                absent: 0,
+                failures: 0,
+                opens: 0,
+                source: ReplaySource::new(entries),
+                shutdown: shutdown.clone(),
+            }
+        }
+    }
+
+    impl DeviceBus for FakeBus {
+        fn scan(&mut self) -> HidResult<Vec<Interface>> {
+            if self.absent > 0 {
+                self.absent -= 1;
+                return Ok(vec![]);
+            }
+            Ok(vec![Interface {
+                id: id("/dev/hidraw0"),
+                vid: 0x1532,
+                pid: 0x0517,
+                usage_page: 0x0c,
+                number: 3,
+            }])
+        }
+
+        fn open(&mut self, _interface: &Interface) -> HidResult<Box<dyn ReportSource>> {
+            self.opens += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(HidError::HidApiError {
+                    message: String::from("Busy"),
+                });
+            }
+            Ok(Box::new(Ending {
+                source: self.source.clone(),
+                shutdown: self.shutdown.clone(),
+            }))
+        }
+    }
+
+    /// Stands in for udev: announces a device event after a short wait, counting the waits.
+    #[derive(Default)]
+    struct EventMonitor {
+        waits: usize,
+    }
+
+    impl HotplugMonitor for EventMonitor {
+        fn wait(&mut self, timeout: Duration) -> bool {
+            self.waits += 1;
+            thread::sleep(timeout.min(Duration::from_millis(5)));
+            true
+        }
+    }
+
+    /// Handlers connecting to `mixer`, with `wait` set as under `[device]`.
+    fn context(mixer: &RecordingMixer, wait: bool) -> HandlerContext {
+        let mut config = Config::default();
+        config.device.wait = wait;
+        let control = Control {
+            watcher: ConfigWatcher::idle(),
+            shutdown: Shutdown::default(),
+        };
+        let mixer = mixer.clone();
+        let connect: Connect = Arc::new(move |_: &BackendConfig| {
+            Ok(Box::new(mixer.connect()) as Box<dyn AudioBackend>)
+        });
+        HandlerContext::new(&config, false, control, None, None, connect)
+    }
+
+    /// Supervises `bus` with an `EventMonitor`, returning the result and the number of waits.
+    fn run(context: &HandlerContext, bus: &mut dyn DeviceBus) -> (Result<(), Error>, usize) {
+        let mut monitor = EventMonitor::default();
+        let result = supervise(context, bus, &mut monitor);
+        (result, monitor.waits)
+    }
+
+    fn stereo_mixer() -> RecordingMixer {
+        RecordingMixer::new(Curve::Cubic.volume(0.5), 2)
+    }
+
+    #[test]
+    fn reconnects_a_device_after_a_disconnect_and_resumes() {
+        let mixer = stereo_mixer();
+        let context = context(&mixer, false);
+        let turn = ReplayEntry::Report(vec![1, 233]);
+        let entries = vec![turn.clone(), ReplayEntry::Disconnect, turn];
+        let mut bus = FakeBus::new(entries, &context.control.shutdown);
+        let (result, waits) = run(&context, &mut bus);
+        assert!(result.is_ok());
+        assert_eq!(
+            mixer.take_calls(),
+            vec![Call::Volume(at(55.0)), Call::Volume(at(60.0))]
+        );
+        assert!(bus.opens >= 2);
+        assert!(waits > 0);
+    }
+
+    #[test]
+    fn waits_for_a_device_that_fails_to_open() {
+        let mixer = stereo_mixer();
+        let context = context(&mixer, true);
+        let mut bus = FakeBus::new(
+            vec![ReplayEntry::Report(vec![1, 233])],
+            &context.control.shutdown,
+        );
+        bus.failures = 2;
+        let (result, _) = run(&context, &mut bus);
+        assert!(result.is_ok());
+        assert_eq!(mixer.take_calls(), vec![Call::Volume(at(55.0))]);
+        assert!(bus.opens >= 3);
+    }
+
+    #[test]
+    fn waits_for_a_device_to_be_plugged_in() {
+        let mixer = stereo_mixer();
+        let context = context(&mixer, true);
+        let mut bus = FakeBus::new(
+            vec![ReplayEntry::Report(vec![1, 234])],
+            &context.control.shutdown,
+        );
+        bus.absent = 3;
+        let (result, waits) = run(&context, &mut bus);
+        assert!(result.is_ok());
+        assert_eq!(mixer.take_calls(), vec![Call::Volume(at(45.0))]);
+        assert!(waits >= 3);
+    }
+
+    #[test]
+    fn fails_without_waiting_when_the_device_cannot_be_opened() {
+        let mixer = stereo_mixer();
+        let context = context(&mixer, false);
+        let mut bus = FakeBus::new(vec![], &context.control.shutdown);
+        bus.failures = 1;
+        let (result, waits) = run(&context, &mut bus);
+        assert_eq!(result.unwrap_err().exit_code(), 69);
+        assert_eq!((bus.opens, waits), (1, 0));
+        assert!(mixer.take_calls().is_empty());
+    }
+
+    #[test]
+    fn fails_without_waiting_when_no_device_is_plugged_in() {
+        let context = context(&stereo_mixer(), false);
+        let mut bus = FakeBus::new(vec![], &context.control.shutdown);
+        bus.absent = 1;
+        let (result, _) = run(&context, &mut bus);
+        assert!(result.is_err());
+        assert_eq!(bus.opens, 0);
+    }
+
+    #[test]
+    fn a_shutdown_ends_the_wait_between_scans_within_a_tick() {
+        let context = context(&stereo_mixer(), true);
+        let mut bus = FakeBus::new(vec![], &context.control.shutdown);
+        bus.absent = usize::MAX;
+        let shutdown = context.control.shutdown.clone();
+        let started = Instant::now();
+        let requester = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            shutdown.request();
+        });
+        let mut monitor = crate::hotplug::PollMonitor;
+        assert!(supervise(&context, &mut bus, &mut monitor).is_ok());
+        requester.join().unwrap();
+        assert!(started.elapsed() < SCAN_INTERVAL);
+    }
+
+    /// Memory sinks behind one connection, told apart by name; the first is the default.
+    struct Sinks(Vec<(String, Box<dyn AudioBackend>)>);
+
+    impl Sinks {
+        fn connect(mixers: &[RecordingMixer]) -> Self {
+            let sinks = mixers.iter().map(|mixer| {
+                let mut backend: Box<dyn AudioBackend> = Box::new(mixer.connect());
+                (backend.default_sink().unwrap().name, backend)
+            });
+            Sinks(sinks.collect())
+        }
+
+        fn backend(&mut self, name: &str) -> Result<&mut Box<dyn AudioBackend>, AudioError> {
+            self.0
+                .iter_mut()
+                .find(|(sink, _)| sink == name)
+                .map(|(_, backend)| backend)
+                .ok_or_else(|| AudioError::NotFound(format!("No such sink: {}", name)))
+        }
+    }
+
+    impl AudioBackend for Sinks {
+        fn default_sink(&mut self) -> Result<Sink, AudioError> {
+            self.0[0].1.default_sink()
+        }
+
+        fn find_sink(&mut self, name: &str) -> Result<Sink, AudioError> {
+            self.backend(name)?.find_sink(name)
+        }
+
+        fn volume(&mut self, sink: &Sink) -> Result<ChannelVolumes, AudioError> {
+            self.backend(&sink.name)?.volume(sink)
+        }
+
+        fn set_volume(&mut self, sink: &Sink, volumes: &ChannelVolumes) -> Result<(), AudioError> {
+            self.backend(&sink.name)?.set_volume(sink, volumes)
+        }
+
+        fn mute(&mut self, sink: &Sink) -> Result<bool, AudioError> {
+            self.backend(&sink.name)?.mute(sink)
+        }
+
+        fn set_mute(&mut self, sink: &Sink, mute: bool) -> Result<(), AudioError> {
+            self.backend(&sink.name)?.set_mute(sink, mute)
+        }
+
+        fn set_default_sink(&mut self, sink: &Sink) -> Result<(), AudioError> {
+            self.backend(&sink.name)?.set_default_sink(sink)
+        }
+    }
+
+    /// Fails the read, as `source` does at its disconnect, once `unplugged` is set.
+    struct Unplugging {
+        source: ReplaySource,
+        unplugged: Arc<AtomicBool>,
+    }
+
+    impl ReportSource for Unplugging {
+        fn read_report(&mut self, buf: &mut [u8], timeout: Duration) -> HidResult<Read> {
+            let read = self.source.read_report(buf, timeout);
+            if read.is_err() {
+                self.unplugged.store(true, Ordering::SeqCst);
+            }
+            read
+        }
+    }
+
+    /// Times out until `unplugged` is set, then reads from `source`.
+    struct AfterUnplug {
+        source: Ending,
+        unplugged: Arc<AtomicBool>,
+    }
+
+    impl ReportSource for AfterUnplug {
+        fn read_report(&mut self, buf: &mut [u8], timeout: Duration) -> HidResult<Read> {
+            if !self.unplugged.load(Ordering::SeqCst) {
+                thread::sleep(timeout.min(Duration::from_millis(5)));
+                return Ok(Read::TimedOut);
+            }
+            self.source.read_report(buf, timeout)
+        }
+    }
+
+    /// A bus with two Nommo 2s: the one at /dev/hidraw0, serial `A1`, turns its knob up and
+    /// is unplugged; the one at /dev/hidraw1 then turns its knob down.
+    struct TwoDevices {
+        unplugged: Arc<AtomicBool>,
+        opened: Vec<String>,
+        shutdown: Shutdown,
+    }
+
+    impl DeviceBus for TwoDevices {
+        fn scan(&mut self) -> HidResult<Vec<Interface>> {
+            let nommo = |path: &str, serial: Option<&str>| Interface {
+                id: DeviceId {
+                    path: path.to_string(),
+                    serial: serial.map(String::from),
+                },
+                vid: 0x1532,
+                pid: 0x0517,
+                usage_page: 0x0c,
+                number: 3,
+            };
+            let mut interfaces = vec![nommo("/dev/hidraw1", None)];
+            if !self.unplugged.load(Ordering::SeqCst) {
+                interfaces.push(nommo("/dev/hidraw0", Some("A1")));
+            }
+            Ok(interfaces)
+        }
+
+        fn open(&mut self, interface: &Interface) -> HidResult<Box<dyn ReportSource>> {
+            self.opened.push(interface.id.path.clone());
+            let unplugged = self.unplugged.clone();
+            if interface.id.path == "/dev/hidraw0" {
+                let entries = vec![ReplayEntry::Report(vec![1, 233]), ReplayEntry::Disconnect];
+                return Ok(Box::new(Unplugging {
+                    source: ReplaySource::new(entries),
+                    unplugged,
+                }));
+            }
+            let source = Ending {
+                source: ReplaySource::new(vec![ReplayEntry::Report(vec![1, 234])]),
+                shutdown: self.shutdown.clone(),
+            };
+            Ok(Box::new(AfterUnplug { source, unplugged }))
+        }
+    }
+
+    #[test]
+    fn each_device_controls_the_sink_bound_to_it() {
+        let named = |name| RecordingMixer::named(name, Curve::Cubic.volume(0.5), 2);
+        let (desk, tv) = (named("desk"), named("tv"));
+        let mut context = context(&stereo_mixer(), false);
+        context.config.bindings = vec![
+            Binding {
+                serial: Some(String::from("A1")),
+                path: None,
+                sink: Some(String::from("desk")),
+                curve: None,
+            },
+            Binding {
+                serial: None,
+                path: Some(String::from("/dev/hidraw1")),
+                sink: Some(String::from("tv")),
+                curve: None,
+            },
+        ];
+        let mixers = [stereo_mixer(), desk.clone(), tv.clone()];
+        context.connect = Arc::new(move |_: &BackendConfig| {
+            Ok(Box::new(Sinks::connect(&mixers)) as Box<dyn AudioBackend>)
+        });
+        let mut bus = TwoDevices {
+            unplugged: Arc::default(),
+            opened: vec![],
+            shutdown: context.control.shutdown.clone(),
+        };
+        let (result, _) = run(&context, &mut bus);
+        assert!(result.is_ok());
+        assert_eq!(desk.take_calls(), vec![Call::Volume(at(55.0))]);
+        // the handler of the other device carried on after this one was unplugged
+        assert_eq!(tv.take_calls(), vec![Call::Volume(at(45.0))]);
+        bus.opened.sort();
+        assert_eq!(bus.opened, vec!["/dev/hidraw0", "/dev/hidraw1"]);
+    }
+
+    #[test]
+    fn backs_off_a_failing_device() {
+        let mut devices = Devices::default();
+        let now = Instant::now();
+        devices.fail(&id("a"), now);
+        assert!(!devices.ready(&id("a"), now));
+        assert!(devices.ready(&id("a"), now + Duration::from_millis(250)));
+        assert!(devices.ready(&id("b"), now));
+
+        devices.fail(&id("a"), now);
+        assert!(!devices.ready(&id("a"), now + Duration::from_millis(250)));
+        assert!(devices.ready(&id("a"), now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn skips_devices_with_a_handler() {
+        let mut devices = Devices::default();
+        devices.active.insert(id("a"));
+        assert!(!devices.ready(&id("a"), Instant::now()));
+    }
+
+    #[test]
+    fn forgets_failures_of_unplugged_devices() {
+        let mut devices = Devices::default();
+        let now = Instant::now();
+        devices.fail(&id("a"), now);
+        devices.fail(&id("b"), now);
+        devices.forget_missing(&vec![id("b")].into_iter().collect());
+        assert!(devices.ready(&id("a"), now));
+        assert!(!devices.ready(&id("b"), now));
+    }
+}